@@ -0,0 +1,69 @@
+//! Throughput of `send` / `recv` over the in-memory connection of `mews::testing`,
+//! free of socket noise, as a baseline for performance work.
+//!
+//! ```sh
+//! cargo bench --features rt_tokio # or any other runtime
+//! ```
+
+use mews::{Message, testing::scripted};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 10;
+
+fn main() {
+    for (name, size, count) in [("small", 16, 100_000), ("large", 1 << 20, 64)] {
+        bench(&format!("recv {name}"), size, count, || recv(size, count));
+        bench(&format!("send {name}"), size, count, || send(size, count));
+    }
+}
+
+/// time to receive `count` binary messages of `size` bytes
+fn recv(size: usize, count: usize) -> Duration {
+    let (conn, _) = scripted(vec![Message::Binary(vec![0x42; size]); count]);
+    let started = Instant::now();
+    block_on(async {
+        for _ in 0..count {
+            let Ok(Some(Message::Binary(payload))) = conn.recv().await else {panic!("expected a binary message")};
+            assert_eq!(payload.len(), size);
+        }
+    });
+    started.elapsed()
+}
+
+/// time to send `count` binary messages of `size` bytes
+fn send(size: usize, count: usize) -> Duration {
+    let (conn, outbound) = scripted(vec![]);
+    let messages = vec![Message::Binary(vec![0x42; size]); count];
+    let started = Instant::now();
+    block_on(async {
+        for message in messages {
+            conn.send(message).await.unwrap();
+        }
+    });
+    let elapsed = started.elapsed();
+    assert!(outbound.bytes().len() > size * count);
+    elapsed
+}
+
+/// report the median of `RUNS` runs of `run`
+fn bench(name: &str, size: usize, count: usize, run: impl Fn() -> Duration) {
+    let mut times = (0..RUNS).map(|_| run()).collect::<Vec<_>>();
+    times.sort();
+    let median = times[RUNS / 2].as_secs_f64();
+    println!("{name:<12} {count:>7} x {size:>8} B : {:>9.3} ms, {:>12.0} msg/s, {:>9.1} MiB/s",
+        median * 1e3,
+        count as f64 / median,
+        (size * count) as f64 / median / (1 << 20) as f64,
+    );
+}
+
+/// drive a future on the in-memory IO ( always ready ) to completion
+fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+    let mut future = std::pin::pin!(future);
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    loop {
+        if let std::task::Poll::Ready(t) = future.as_mut().poll(&mut cx) {
+            return t
+        }
+    }
+}
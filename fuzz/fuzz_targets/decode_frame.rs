@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mews::{Config, frame::decode_frame};
+
+fuzz_target!(|input: &[u8]| {
+    let config = Config {
+        max_frame_size:         Some(1 << 20),
+        accept_unmasked_frames: true,
+        ..Default::default()
+    };
+
+    let mut input = input;
+    while let Ok(Some((frame, size))) = decode_frame(input, &config) {
+        assert!(size <= input.len());
+        assert!(frame.payload.len() <= size);
+        input = &input[size..];
+    }
+});
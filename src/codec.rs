@@ -0,0 +1,179 @@
+//! Convenience layers over [`Connection`](crate::Connection).
+
+use crate::{Message, connection::{Connection, UnderlyingConnection}};
+use std::io::{Error, ErrorKind};
+
+/// # Length-delimited records over binary messages
+///
+/// Each record is prefixed with its length as big-endian `u32` and carried
+/// in binary messages. A record may span multiple messages, and a message
+/// may contain multiple records.
+///
+/// Records larger than `Config::max_message_size` are rejected.
+///
+/// *example.rs*
+/// ```
+/// # use mews::{Connection, codec::LengthDelimited};
+/// #
+/// async fn handler(conn: Connection) {
+///     let mut records = LengthDelimited::new(&conn);
+///     while let Ok(Some(record)) = records.recv().await {
+///         records.send(&record).await
+///             .expect("failed to send record");
+///     }
+/// }
+/// ```
+pub struct LengthDelimited<'c, C: UnderlyingConnection = crate::runtime::TcpStream> {
+    conn:   &'c Connection<C>,
+    buffer: Vec<u8>,
+}
+
+impl<'c, C: UnderlyingConnection> LengthDelimited<'c, C> {
+    const PREFIX_SIZE: usize = std::mem::size_of::<u32>();
+
+    pub fn new(conn: &'c Connection<C>) -> Self {
+        Self { conn, buffer: Vec::new() }
+    }
+
+    /// Send a record as a binary message.
+    pub async fn send(&mut self, record: &[u8]) -> Result<(), Error> {
+        self.send_all([record]).await
+    }
+
+    /// Send records together in one binary message.
+    pub async fn send_all(&mut self, records: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Result<(), Error> {
+        let mut payload = Vec::new();
+        for record in records {
+            let record = record.as_ref();
+            let len = u32::try_from(record.len())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "record is too large for `u32` length prefix"))?;
+            payload.extend_from_slice(&len.to_be_bytes());
+            payload.extend_from_slice(record);
+        }
+        self.conn.send(Message::Binary(payload)).await
+    }
+
+    /// Await the next record.
+    ///
+    /// returns `Ok(None)` when the peer closed the connection on a record boundary.
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            if let Some(record) = self.next_buffered()? {
+                return Ok(Some(record))
+            }
+
+            match self.conn.recv().await? {
+                Some(Message::Binary(payload)) => self.buffer.extend_from_slice(&payload),
+                Some(Message::Close(_)) => return if self.buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(Error::new(ErrorKind::UnexpectedEof, "connection closed in the middle of a record"))
+                },
+                Some(Message::Text(_)) => return Err(Error::new(
+                    ErrorKind::InvalidData, "Expected binary message for length-delimited records")),
+                Some(Message::Ping(_) | Message::Pong(_) | Message::Reserved { .. }) | None => continue,
+            }
+        }
+    }
+
+    /// Consume the adapter and return the remaining buffered bytes,
+    /// which don't form a complete record.
+    pub fn into_remaining(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    fn next_buffered(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(prefix) = self.buffer.get(..Self::PREFIX_SIZE) else {
+            return Ok(None)
+        };
+
+        let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        if let Some(limit) = &self.conn.config.max_message_size {
+            (&len <= limit).then_some(())
+                .ok_or_else(|| Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Incoming record (size: {len}) is larger than limit ({})", *limit)
+                ))?;
+        }
+
+        if self.buffer.len() < Self::PREFIX_SIZE + len {
+            return Ok(None)
+        }
+        let record = self.buffer[Self::PREFIX_SIZE..(Self::PREFIX_SIZE + len)].to_vec();
+        self.buffer.drain(..(Self::PREFIX_SIZE + len));
+        Ok(Some(record))
+    }
+}
+
+/// Conversion of typed values into messages, for [`TypedConnection`].
+pub trait Encode<T> {
+    fn encode(&self, value: &T) -> Result<Message, Error>;
+}
+
+/// Conversion of text or binary messages into typed values, for [`TypedConnection`].
+pub trait Decode<T> {
+    fn decode(&self, message: Message) -> Result<T, Error>;
+}
+
+/// # Typed values over messages
+///
+/// Each value is converted from/into a text or binary message by `Codec`,
+/// e.g. of CBOR or JSON, so that the handler works with `T` instead of `Message`.
+///
+/// *example.rs*
+/// ```
+/// # use mews::{Connection, Message, codec::{TypedConnection, Encode, Decode}};
+/// # use std::io::{Error, ErrorKind};
+/// #
+/// struct Decimal;
+/// impl Encode<u64> for Decimal {
+///     fn encode(&self, value: &u64) -> Result<Message, Error> {
+///         Ok(Message::Text(value.to_string()))
+///     }
+/// }
+/// impl Decode<u64> for Decimal {
+///     fn decode(&self, message: Message) -> Result<u64, Error> {
+///         match message {
+///             Message::Text(text) => text.parse().map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+///             _ => Err(Error::new(ErrorKind::InvalidData, "Expected text message")),
+///         }
+///     }
+/// }
+///
+/// async fn handler(conn: Connection) {
+///     let numbers = TypedConnection::new(&conn, Decimal);
+///     while let Ok(Some(n)) = numbers.recv().await {
+///         numbers.send(&(n * 2)).await
+///             .expect("failed to send number");
+///     }
+/// }
+/// ```
+pub struct TypedConnection<'c, T, Codec, C: UnderlyingConnection = crate::runtime::TcpStream> {
+    conn:  &'c Connection<C>,
+    codec: Codec,
+    value: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<'c, T, Codec: Encode<T> + Decode<T>, C: UnderlyingConnection> TypedConnection<'c, T, Codec, C> {
+    pub fn new(conn: &'c Connection<C>, codec: Codec) -> Self {
+        Self { conn, codec, value: std::marker::PhantomData }
+    }
+
+    /// Send a value as the message encoded by the codec.
+    pub async fn send(&self, value: &T) -> Result<(), Error> {
+        self.conn.send(self.codec.encode(value)?).await
+    }
+
+    /// Await the next text or binary message and decode it by the codec.
+    ///
+    /// returns `Ok(None)` when the peer closed the connection.
+    pub async fn recv(&self) -> Result<Option<T>, Error> {
+        loop {
+            match self.conn.recv().await? {
+                Some(message @ (Message::Text(_) | Message::Binary(_))) => return self.codec.decode(message).map(Some),
+                Some(Message::Close(_)) => return Ok(None),
+                Some(Message::Ping(_) | Message::Pong(_) | Message::Reserved { .. }) | None => continue,
+            }
+        }
+    }
+}
@@ -1,29 +1,173 @@
-use crate::{Config, Message};
-use crate::runtime::{Read, Write, RwLock};
+use crate::{Config, Message, RecvInfo};
+use crate::message::MessageReader;
+use crate::frame::{Frame, OpCode};
+use split::FlushPolicy;
+use crate::runtime::{Read, Write, RwLock, Mutex};
 use std::{sync::Arc, io::Error};
 
 // Why 'static lifetime?
-// 
+//
 // [__splitref__]
 // 1. The underlying connection is in `Arc`
-// 2. The closer returned from `Connection::new` is expected to be alive until WebSocket session completes
+// 2. Everything holding the halves ( `Connection`, its `ReadHalf` / `WriteHalf`, `Closer`
+//    and `ConnectionManager`'s entries ) also holds the `Arc`, dropping it after the halves
 // 3. This split is expected to be called before user's handler is called
-// 
+//
 // [__clone__]
 // Just a dummy lifetime paramter to have the same signature of it in __splitref__
-pub trait UnderlyingConnection: Read + Write + Unpin + split::Splitable<'static> + 'static {}
-impl<T: Read + Write + Unpin + split::Splitable<'static> + 'static> UnderlyingConnection for T {}
+pub trait UnderlyingConnection: Read + Write + Unpin + split::Splitable<'static, ReadHalf: 'static, WriteHalf: 'static> + 'static {}
+impl<T: Read + Write + Unpin + split::Splitable<'static, ReadHalf: 'static, WriteHalf: 'static> + 'static> UnderlyingConnection for T {}
 
+type ReadHalfOf<C>  = <C as split::Splitable<'static>>::ReadHalf;
+type WriteHalfOf<C> = <C as split::Splitable<'static>>::WriteHalf;
+
+/// # WebSocket Connection
+///
+/// `recv` and `send` ( and `write`, `flush` ) take `&self`, so they can be
+/// performed concurrently, e.g. in two branches of a `select!`, without
+/// [`split`](Connection::split)ting.
+///
+/// **note** : Interleaving is done per message :
+///
+/// * `send` / `write` holds the write side for the whole message, so frames of
+///   different messages never interleave on the wire.
+/// * `recv` holds the read side while reassembling a fragmented message, so
+///   concurrent `recv`s each get whole messages.
 pub struct Connection<C: UnderlyingConnection = crate::runtime::TcpStream> {
     __closed__: Arc<RwLock<bool>>,
 
+    reader: Arc<Mutex<ReadState<ReadHalfOf<C>>>>,
+    writer: Arc<Mutex<WriteState<WriteHalfOf<C>>>>,
+    /// `reader` lent to the `Closer`, see `Lent`
+    lent:   Lent<ReadHalfOf<C>>,
+
+    pub(crate) config:   Config,
+    pub(crate) protocol: Option<String>,
+    peer_addr:           Option<std::net::SocketAddr>,
+    received_unmasked:   Arc<std::sync::atomic::AtomicBool>,
+    activity:            Arc<crate::frame::Activity>,
+    handshake:           Arc<Handshake>,
+
+    /// keeps the underlying connection alive for the halves (dropped last)
     #[cfg(feature="__splitref__")]
-    conn: Arc<std::cell::UnsafeCell<C>>,
-    #[cfg(feature="__clone__")]
-    conn: C,
+    __conn__: Arc<std::cell::UnsafeCell<C>>,
+}
 
-    config:     Config,
-    n_buffered: usize,
+/// the read state lent to `Closer` by `HandlerExitClose::Graceful` to receive
+/// the peer's close frame after the handler exits, until `Connection::split`
+/// takes it back for the `ReadHalf`
+type Lent<R> = Arc<std::sync::Mutex<Option<Arc<Mutex<ReadState<R>>>>>>;
+
+struct ReadState<R> {
+    conn:     R,
+    messages: MessageReader,
+    /// error carrying a `CloseFrame` not sent to the peer yet
+    pending_error: Option<Error>,
+    /// whether the peer's close frame has been received
+    received_close: bool,
+    /// payload of the last `Ping` not answered yet by `Config::coalesce_pongs`
+    pending_pong: Option<Vec<u8>>,
+    /// message received but held until `pending_pong` is sent, not to be lost
+    /// by an error or cancellation in sending it
+    held: Option<(Message, RecvInfo)>,
+    handshake: Arc<Handshake>,
+}
+
+struct WriteState<W> {
+    conn:    W,
+    /// encoded frames to be written to `conn`
+    buffer:  Vec<u8>,
+    /// number of bytes of `buffer` already written
+    written: usize,
+    /// whether a close frame is in `buffer` or already sent
+    closing: bool,
+    /// whether a fragmented data message is started and not finished yet,
+    /// e.g. by a cancelled `send_text_stream` or a dropped `TextWriter`
+    fragmented: bool,
+    /// when the last `Ping` was queued, for `Config::min_ping_interval`
+    last_ping: Option<std::time::Instant>,
+    /// pacing writes by `Config::max_send_bytes_per_second`
+    bucket:  Option<TokenBucket>,
+    handshake: Arc<Handshake>,
+}
+
+/// Progress of the closing handshake, shared by the states for `Connection::closed`.
+#[derive(Default)]
+struct Handshake {
+    sent:      std::sync::atomic::AtomicBool,
+    received:  std::sync::atomic::AtomicBool,
+    /// the connection can't receive anymore, by EOF or the reading side dropped
+    abandoned: std::sync::atomic::AtomicBool,
+    wakers:    std::sync::Mutex<Vec<std::task::Waker>>,
+}
+impl Handshake {
+    fn is_done(&self) -> bool {
+        use std::sync::atomic::Ordering::SeqCst;
+        self.abandoned.load(SeqCst) || (self.sent.load(SeqCst) && self.received.load(SeqCst))
+    }
+
+    fn set(&self, flag: &std::sync::atomic::AtomicBool) {
+        flag.store(true, std::sync::atomic::Ordering::SeqCst);
+        if self.is_done() {
+            let wakers = std::mem::take(&mut *self.wakers.lock().unwrap_or_else(std::sync::PoisonError::into_inner));
+            wakers.into_iter().for_each(std::task::Waker::wake);
+        }
+    }
+
+    async fn done(&self) {
+        std::future::poll_fn(|cx| {
+            if self.is_done() {
+                return std::task::Poll::Ready(())
+            }
+            let mut wakers = self.wakers.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+            if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            /* `set` may have been done before the lock */
+            if self.is_done() {std::task::Poll::Ready(())} else {std::task::Poll::Pending}
+        }).await
+    }
+}
+
+/// Token bucket holding up to a second's worth of bytes.
+struct TokenBucket {
+    rate:        u64,
+    tokens:      u64,
+    refilled_at: std::time::Instant,
+}
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self { rate, tokens: rate, refilled_at: std::time::Instant::now() }
+    }
+
+    /// number of bytes up to `want` allowed to be written now,
+    /// or the duration to wait until a chunk of them is allowed
+    fn take(&mut self, want: usize) -> Result<usize, std::time::Duration> {
+        const NANOS: u128 = 1_000_000_000;
+
+        let now = std::time::Instant::now();
+        let refill = (now - self.refilled_at).as_nanos() * self.rate as u128 / NANOS;
+        if self.tokens + refill as u64 >= self.rate {
+            (self.tokens, self.refilled_at) = (self.rate, now);
+        } else if refill > 0 {
+            self.tokens += refill as u64;
+            self.refilled_at += std::time::Duration::from_nanos((refill * NANOS / self.rate as u128) as u64);
+        }
+
+        match self.tokens {
+            0 => {
+                /* wait for a tenth of the rate rather than every single byte */
+                let chunk = (want as u64).min(self.rate / 10).max(1) as u128;
+                Err(std::time::Duration::from_nanos((chunk * NANOS).div_ceil(self.rate as u128) as u64))
+            }
+            tokens => Ok(want.min(usize::try_from(tokens).unwrap_or(usize::MAX)))
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens = self.tokens.saturating_sub(n as u64);
+    }
 }
 
 /*============================================================*/
@@ -67,85 +211,389 @@ pub struct Connection<C: UnderlyingConnection = crate::runtime::TcpStream> {
         --------------------------------------------|\n\
     ";
 
-    macro_rules! underlying {
-        ($this:expr) => {async {
-            let _: &mut Connection<_> = $this;
-            let conn = (!read_closed(&$this.__closed__).await).then(|| {
-                #[cfg(feature="__splitref__")] {
-                    // SAFETY: `$this` has unique access to `$this.conn` due to the
-                    // mutable = exclusive reference
-                    // 
-                    // (this is based on the precondition that: `$this` and the closer are NOT used at the same time)
-                    unsafe {&mut *$this.conn.get()}
-                }
-                #[cfg(feature="__clone__")] {
-                    &mut $this.conn
-                }
-            });
-            underlying!(@@checked conn)
-        }};
-        ($__closed__:ident, $conn:ident) => {async {
-            let _: &mut _ = $conn;
-            let conn = if (!read_closed(&$__closed__).await) {Some($conn)} else {None};
-            underlying!(@@checked conn)
-        }};
-        (@@checked $this:expr) => {{
-            let _: Option<&mut _> = $this;
-            $this.ok_or_else(|| {
-                #[cfg(debug_assertions)] eprintln! {"{ALREADY_CLOSED_MESSAGE}"}
-                ::std::io::Error::new(::std::io::ErrorKind::ConnectionReset, "WebSocket connection is already closed")
-            })
-        }};
+    /// `conn` as `TcpStream` of the selected runtime, if it is
+    #[inline]
+    fn as_tcp_stream<C: 'static>(conn: &C) -> Option<&crate::runtime::TcpStream> {
+        (conn as &dyn std::any::Any).downcast_ref()
     }
-    #[inline(always)]
-    async fn to_checked_parts<C: UnderlyingConnection>(connection: &mut Connection<C>) -> Result<(&mut C, &Config, &mut usize), Error> {
-        #[cfg(feature="__splitref__")] {
-            let conn = underlying!(connection).await?;
-            return Ok((conn, &connection.config, &mut connection.n_buffered))
+
+    #[inline]
+    fn already_closed() -> Error {
+        #[cfg(debug_assertions)] eprintln! {"{ALREADY_CLOSED_MESSAGE}"}
+        Error::new(std::io::ErrorKind::ConnectionReset, AlreadyClosed)
+    }
+
+    /// reject a close frame of a code never valid on the wire, e.g. `CloseCode::Tls` (1015),
+    /// or of a reason over `CloseFrame::MAX_REASON_SIZE`
+    fn check_sendable(frame: &CloseFrame) -> Result<(), Error> {
+        if !frame.code.is_allowed_to_send() {
+            return Err(Error::new(std::io::ErrorKind::InvalidInput, format!(
+                "Close code {} ({}) is not allowed to be sent",
+                frame.code.as_u16(), frame.code.description()
+            )))
         }
-        #[cfg(feature="__clone__")] {
-            let Connection { conn, __closed__, config, n_buffered } = connection;
-            let conn = underlying!(__closed__, conn).await?;
-            return Ok((conn, config, n_buffered))
+        match frame.reason.as_deref().map(str::len) {
+            Some(len) if len > CloseFrame::MAX_REASON_SIZE => Err(Error::new(std::io::ErrorKind::InvalidInput, format!(
+                "Close reason of {len} bytes is over the limit {}",
+                CloseFrame::MAX_REASON_SIZE
+            ))),
+            _ => Ok(())
         }
     }
 
     #[inline]
-    pub(super) async fn send(
-        message:    Message,
-        conn:       &mut (impl Write + Unpin),
-        config:     &Config,
-        n_buffered: &mut usize,
-    ) -> Result<(), Error> {
-        message.write(conn, config).await?;
-        flush(conn, n_buffered).await?;
-        Ok(())
+    async fn check_not_closed(__closed__: &RwLock<bool>) -> Result<(), Error> {
+        if read_closed(__closed__).await {
+            Err(already_closed())
+        } else {
+            Ok(())
+        }
     }
-    #[inline]
-    pub(super) async fn write(
-        message:    Message,
-        conn:       &mut (impl Write + Unpin),
-        config:     &Config,
-        n_buffered: &mut usize,
-    ) -> Result<usize, Error> {
-        let n = message.write(conn, config).await?;
-        *n_buffered += n;
-        if *n_buffered > config.write_buffer_size {
-            if *n_buffered > config.max_write_buffer_size {
-                panic!("Buffered messages is larger than `max_write_buffer_size`");
-            } else {
-                flush(conn, n_buffered).await?
-            }
-        }
-        Ok(n)
+
+    impl<R: Read + Unpin> ReadState<R> {
+        /// Read a message. When it fails with an error carrying a `CloseFrame`,
+        /// the frame is sent to the peer via `writer` and the connection is closed.
+        /// 
+        /// After we sent a close frame, this keeps reading until the peer's close
+        /// frame, discarding other messages if `Config::discard_after_close`.
+        /// 
+        /// This is cancel-safe : the error is kept until the close frame is sent.
+        #[inline]
+        async fn recv_with_info(&mut self,
+            writer:     &Mutex<WriteState<impl Write + Unpin>>,
+            __closed__: &RwLock<bool>,
+            config:     &Config,
+        ) -> Result<Option<(Message, RecvInfo)>, Error> {
+            if self.pending_error.is_none() {
+                if self.received_close {
+                    return Err(already_closed())
+                }
+                loop {
+                    match self.messages.read(&mut self.conn, config).await {
+                        Ok(Some((Message::Close(frame), info))) => {
+                            #[cfg(feature="tracing")] tracing::debug!(close = ?frame, "received close frame");
+                            self.received_close = true;
+                            self.handshake.set(&self.handshake.received);
+                            return Ok(Some((Message::Close(frame), info)))
+                        }
+                        Ok(None) => {
+                            self.handshake.set(&self.handshake.abandoned);
+                            return Err(CloseFrame::abnormal())
+                        }
+                        Ok(Some(_)) if config.discard_after_close && read_closed(__closed__).await => continue,
+                        Err(e) if CloseFrame::from_error(&e).is_some() => {
+                            self.pending_error = Some(e);
+                            break
+                        }
+                        other => return other
+                    }
+                }
+            }
+
+            let frame = self.pending_error.as_ref().and_then(CloseFrame::from_error).cloned().unwrap();
+            if let Err(e) = writer.lock().await.close_with(frame, __closed__, config).await {
+                eprintln!("failed to send a close message: {e}")
+            }
+            Err(self.pending_error.take().unwrap())
+        }
     }
-    #[inline]
-    pub(super) async fn flush(
-        conn:       &mut (impl Write + Unpin),
-        n_buffered: &mut usize,
-    ) -> Result<(), Error> {
-        conn.flush().await
-            .map(|_| *n_buffered = 0)
+
+    impl<R> Drop for ReadState<R> {
+        fn drop(&mut self) {
+            self.handshake.set(&self.handshake.abandoned);
+        }
+    }
+
+    #[cfg(feature="tracing")]
+    impl<W> Drop for WriteState<W> {
+        fn drop(&mut self) {
+            tracing::debug!("dropped connection");
+        }
+    }
+
+    impl<W: Write + Unpin> WriteState<W> {
+        fn new(conn: W) -> Self {
+            Self { conn, buffer: Vec::new(), written: 0, closing: false, fragmented: false, last_ping: None, bucket: None, handshake: Default::default() }
+        }
+
+        /// number of bytes encoded but not written to the connection yet
+        #[inline]
+        fn n_buffered(&self) -> usize {
+            self.buffer.len() - self.written
+        }
+
+        #[inline]
+        async fn check_open(&self, __closed__: &RwLock<bool>) -> Result<(), Error> {
+            if self.closing {
+                return Err(already_closed())
+            }
+            check_not_closed(__closed__).await
+        }
+
+        /// Encode `message` into the buffer, returning the number of bytes.
+        /// 
+        /// A `Ping` within `Config::min_ping_interval` is dropped here,
+        /// a close frame is rewritten by `Config::on_close_send` and rejected
+        /// if it's not allowed to send, and a `Reserved` message is rejected
+        /// if its opcode isn't a reserved one. An unfinished fragmented message
+        /// is finished before a data message.
+        #[inline]
+        fn queue(&mut self, mut message: Message, config: &Config) -> Result<usize, Error> {
+            if matches!(message, Message::Text(_) | Message::Binary(_) | Message::Reserved { opcode: 0x3..=0x7, .. }) {
+                self.finish_fragmented();
+            }
+            if let Message::Reserved { opcode, .. } = &message {
+                if !matches!(opcode, 0x3..=0x7 | 0xb..=0xf) {
+                    return Err(Error::new(std::io::ErrorKind::InvalidInput, format!(
+                        "Opcode {opcode:#x} is not a reserved one to be sent as `Message::Reserved`"
+                    )))
+                }
+            }
+            if let Message::Close(Some(frame)) = message {
+                let frame = match &config.on_close_send {
+                    Some(hook) => hook.apply(frame),
+                    None => frame
+                };
+                check_sendable(&frame)?;
+                message = Message::Close(Some(frame));
+            }
+            if matches!(message, Message::Ping(_)) {
+                let now = std::time::Instant::now();
+                if let (Some(interval), Some(last)) = (config.min_ping_interval, self.last_ping) {
+                    if now.duration_since(last) < interval {
+                        return Ok(0)
+                    }
+                }
+                self.last_ping = Some(now);
+            }
+            #[cfg(feature="tracing")]
+            if let Message::Close(frame) = &message {
+                tracing::debug!(close = ?frame, "sending close frame");
+            }
+            self.closing |= matches!(message, Message::Close(_));
+            Ok(message.encode(&mut self.buffer, config))
+        }
+
+        /// Write all the buffered bytes to the connection and flush it.
+        /// 
+        /// This is cancel-safe : the bytes are written from where the last
+        /// cancelled `drain` stopped.
+        async fn drain(&mut self, __closed__: &RwLock<bool>) -> Result<(), Error> {
+            while self.written < self.buffer.len() {
+                let mut end = self.buffer.len();
+                if let Some(bucket) = &mut self.bucket {
+                    loop {
+                        match bucket.take(end - self.written) {
+                            Ok(allowed) => break end = self.written + allowed,
+                            Err(wait) => crate::runtime::sleep(wait).await
+                        }
+                    }
+                }
+                match self.conn.write(&self.buffer[self.written..end]).await? {
+                    0 => return Err(Error::new(std::io::ErrorKind::WriteZero, "failed to write frames to the connection")),
+                    n => {
+                        self.written += n;
+                        if let Some(bucket) = &mut self.bucket {
+                            bucket.consume(n);
+                        }
+                    }
+                }
+            }
+            self.buffer.clear();
+            self.written = 0;
+
+            self.conn.flush().await?;
+            if self.closing && !read_closed(__closed__).await {
+                set_closed(__closed__).await;
+                self.handshake.set(&self.handshake.sent);
+                #[cfg(feature="tracing")] tracing::debug!("sent close frame");
+            }
+
+            Ok(())
+        }
+
+        #[inline]
+        async fn send(&mut self,
+            message:    Message,
+            __closed__: &RwLock<bool>,
+            config:     &Config,
+        ) -> Result<(), Error> {
+            self.check_open(__closed__).await?;
+            self.queue(message, config)?;
+            self.drain(__closed__).await
+        }
+
+        #[inline]
+        async fn write(&mut self,
+            message:    Message,
+            __closed__: &RwLock<bool>,
+            config:     &Config,
+        ) -> Result<usize, Error> {
+            self.check_open(__closed__).await?;
+            let n = self.queue(message, config)?;
+            if self.n_buffered() > config.write_buffer_size {
+                if self.n_buffered() > config.max_write_buffer_size {
+                    panic!("Buffered messages is larger than `max_write_buffer_size`");
+                } else {
+                    self.drain(__closed__).await?
+                }
+            }
+            Ok(n)
+        }
+
+        #[inline]
+        async fn flush(&mut self,
+            __closed__: &RwLock<bool>,
+        ) -> Result<(), Error> {
+            check_not_closed(__closed__).await?;
+            self.drain(__closed__).await
+        }
+
+        /// Send a `Pong` borrowing the payload, to recycle it after this.
+        async fn send_pong(&mut self,
+            payload:    &[u8],
+            __closed__: &RwLock<bool>,
+        ) -> Result<(), Error> {
+            self.check_open(__closed__).await?;
+            Frame::encode_unmasked_parts(true, OpCode::Pong, payload, &mut self.buffer);
+            self.drain(__closed__).await
+        }
+
+        /// Send a frame received from another connection, validated the same
+        /// as by `send` : a control frame is queued as its message ( e.g. a
+        /// close frame is rewritten by `Config::on_close_send` ), and a data
+        /// frame is fragmented by `Config::max_frame_size`.
+        async fn send_frame(&mut self,
+            frame:      Frame,
+            __closed__: &RwLock<bool>,
+            config:     &Config,
+        ) -> Result<(), Error> {
+            self.check_open(__closed__).await?;
+            match frame.opcode {
+                OpCode::Close | OpCode::Ping | OpCode::Pong => {
+                    self.queue(Message::from_control_frame(frame)?, config)?;
+                }
+                OpCode::Reserved(opcode) => {
+                    self.queue(Message::Reserved { opcode, payload: frame.payload }, config)?;
+                }
+                opcode @ (OpCode::Text | OpCode::Binary | OpCode::Continue) => {
+                    self.queue_fragment(opcode, frame.payload, opcode != OpCode::Continue, frame.is_final, config)?;
+                }
+            }
+            self.drain(__closed__).await
+        }
+
+        /// Send pre-encoded frames as they are, except that a close frame is
+        /// queued as its message to close the same as by `send` ( e.g. rewritten
+        /// by `Config::on_close_send` ).
+        async fn send_raw(&mut self,
+            bytes:      &[u8],
+            __closed__: &RwLock<bool>,
+            config:     &Config,
+        ) -> Result<(), Error> {
+            let mut frames = Frame::split_frames(bytes)
+                .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidInput, "`send_raw` got bytes not of valid frames"))?;
+            let close = match frames.last() {
+                Some((frame, _)) if frame.opcode == OpCode::Close => {
+                    let (frame, _) = frames.pop().unwrap();
+                    Some(Message::from_control_frame(frame).map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e))?)
+                }
+                _ => None
+            };
+            if frames.iter().any(|(frame, _)| frame.opcode == OpCode::Close) {
+                return Err(Error::new(std::io::ErrorKind::InvalidInput, "`send_raw` got frames following a close frame"))
+            }
+
+            self.check_open(__closed__).await?;
+            for (frame, raw) in frames {
+                if matches!(frame.opcode, OpCode::Text | OpCode::Binary | OpCode::Continue) {
+                    self.fragmented = !frame.is_final;
+                }
+                self.buffer.extend_from_slice(raw);
+            }
+            if let Some(close) = close {
+                self.queue(close, config)?;
+            }
+            self.drain(__closed__).await
+        }
+
+        /// Send the parts from `source` as one data message of `opcode`,
+        /// flushed by `flush`.
+        async fn send_stream(&mut self,
+            opcode:     OpCode,
+            source:     impl futures_core::Stream<Item = Vec<u8>>,
+            flush:      FlushPolicy,
+            __closed__: &RwLock<bool>,
+            config:     &Config,
+        ) -> Result<(), Error> {
+            self.check_open(__closed__).await?;
+
+            /* keep one part back to know which is the last */
+            let mut source = std::pin::pin!(source);
+            let (mut part, mut n) = (std::future::poll_fn(|cx| source.as_mut().poll_next(cx)).await.unwrap_or_default(), 1);
+            loop {
+                let following = std::future::poll_fn(|cx| source.as_mut().poll_next(cx)).await;
+                let is_final = following.is_none();
+                self.check_open(__closed__).await?;
+                self.queue_fragment(opcode, std::mem::take(&mut part), n == 1, is_final, config)?;
+                self.drain_if(flush.flushes(n, is_final), __closed__, config).await?;
+
+                match following {
+                    Some(following) => (part, n) = (following, n + 1),
+                    None => return Ok(())
+                }
+            }
+        }
+
+        /// Encode `payload` into the buffer as a part of a data message of `opcode`.
+        /// 
+        /// An unfinished fragmented message is finished before the first part,
+        /// and a following part fails if its message has been finished so.
+        fn queue_fragment(&mut self, opcode: OpCode, payload: Vec<u8>, is_first: bool, is_final: bool, config: &Config) -> Result<(), Error> {
+            if is_first {
+                self.finish_fragmented();
+            } else if !self.fragmented {
+                return Err(Error::new(std::io::ErrorKind::InvalidInput,
+                    "The fragmented message has been finished before this part, by another data message"))
+            }
+            for frame in Frame::fragments(opcode, payload, is_first, is_final, config) {
+                frame.encode_unmasked(&mut self.buffer);
+            }
+            self.fragmented = !is_final;
+            Ok(())
+        }
+
+        /// Encode an empty final continuation frame into the buffer to finish
+        /// the fragmented message left unfinished, if any.
+        fn finish_fragmented(&mut self) {
+            if std::mem::take(&mut self.fragmented) {
+                Frame::encode_unmasked_parts(true, OpCode::Continue, &[], &mut self.buffer);
+            }
+        }
+
+        /// `drain` if `flush` or the buffer is over `Config::write_buffer_size`.
+        async fn drain_if(&mut self, flush: bool, __closed__: &RwLock<bool>, config: &Config) -> Result<(), Error> {
+            if flush || self.n_buffered() > config.write_buffer_size {
+                self.drain(__closed__).await?
+            }
+            Ok(())
+        }
+
+        /// Send `frame` to close the connection unless it's already closed,
+        /// or complete sending the close frame already buffered.
+        async fn close_with(&mut self,
+            frame:      CloseFrame,
+            __closed__: &RwLock<bool>,
+            config:     &Config,
+        ) -> Result<(), Error> {
+            if read_closed(__closed__).await {
+                return Ok(())
+            }
+            if !self.closing {
+                self.queue(Message::Close(Some(frame)), config)?;
+            }
+            self.drain(__closed__).await
+        }
     }
 /*============================================================*/
 /* end utils                                                  */
@@ -155,50 +603,78 @@ const _: (/* trait impls */) = {
     unsafe impl<C: UnderlyingConnection> Send for Connection<C> {}
     unsafe impl<C: UnderlyingConnection> Sync for Connection<C> {}
 
-    impl<C: UnderlyingConnection + std::fmt::Debug> std::fmt::Debug for Connection<C> {
+    impl<C: UnderlyingConnection> std::fmt::Debug for Connection<C> {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             f.debug_struct("WebSocket Connection")
-                .field("underlying", {
-                    #[cfg(feature="__splitref__")] {
-                        &unsafe {&*self.conn.get()}
-                    }
-                    #[cfg(feature="__clone__")] {
-                        &self.conn
-                    }
-                })
+                .field("peer_addr", &self.peer_addr)
                 .field("config", &self.config)
-                .field("n_buffered", &self.n_buffered)
-                .finish()
+                .finish_non_exhaustive()
         }
     }
 };
 
 /// # WebSocket Connection Closer
-/// 
+///
 /// Created together with `Connection` by `Connection::new`, and used to
 /// ensure sending close message to client before shutdown.
-/// 
+///
 /// This is a workaround for that we can't perform async `Drop`
 /// in stable way. `Closer` is tend to be used like `Drop` process
 /// of the corresponded `Connection`.
-pub struct Closer<C: UnderlyingConnection>(Connection<C>);
+pub struct Closer<C: UnderlyingConnection> {
+    __closed__: Arc<RwLock<bool>>,
+    writer:     Arc<Mutex<WriteState<WriteHalfOf<C>>>>,
+    lent:       Lent<ReadHalfOf<C>>,
+    config:     Config,
+    handshake:  Arc<Handshake>,
+
+    #[cfg(feature="__splitref__")]
+    __conn__: Arc<std::cell::UnsafeCell<C>>,
+}
+
+unsafe impl<C: UnderlyingConnection> Send for Closer<C> {}
+unsafe impl<C: UnderlyingConnection> Sync for Closer<C> {}
 
 use crate::{CloseCode, CloseFrame};
+/// default timeout of [`Connection::shutdown`]
+pub const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// how long [`WriteHalf::close_now`](split::WriteHalf::close_now) tries to write the close frame
+pub const CLOSE_NOW_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// `Config::default_close` or `CloseCode::Normal` without reason
+fn default_close(config: &Config) -> CloseFrame {
+    config.default_close.clone().unwrap_or(CloseFrame {
+        code:   CloseCode::Normal,
+        reason: None
+    })
+}
+
 impl<C: UnderlyingConnection> Closer<C> {
-    /// if the connection is not closed yet, send a close frame with
-    /// `CloseCode::Normal`. see [`send_close_if_not_closed_with`](Closer::send_close_if_not_closed_with)
+    /// Wait until the closing handshake completes, see [`Connection::closed`].
+    /// 
+    /// Once the handler dropped the `Connection` ( or its `ReadHalf` ),
+    /// this returns immediately, unless the `Connection` is kept receiving
+    /// by `HandlerExitClose::Graceful`.
+    pub async fn wait_closed(&self) {
+        self.handshake.done().await
+    }
+
+    /// if the connection is not closed yet, send a close frame of
+    /// `Config::default_close` (`CloseCode::Normal` by default).
+    /// see [`send_close_if_not_closed_with`](Closer::send_close_if_not_closed_with)
     /// to do with custom frame.
     pub async fn send_close_if_not_closed(self) {
-        self.send_close_if_not_closed_with(CloseFrame {
-            code:   CloseCode::Normal,
-            reason: None
-        }).await
+        let frame = default_close(&self.config);
+        self.send_close_if_not_closed_with(frame).await
     }
 
     /// if the connection is not closed yet, send the close frame.
-    pub async fn send_close_if_not_closed_with(mut self, frame: CloseFrame) {
+    /// 
+    /// With `HandlerExitClose::Graceful` of `Config::handler_exit_close`, this
+    /// then awaits the peer's close frame up to its timeout.
+    pub async fn send_close_if_not_closed_with(self, frame: CloseFrame) {
         #[cfg(debug_assertions)] {
-            if Arc::strong_count(&self.0.__closed__) != 1 {
+            if Arc::strong_count(&self.__closed__) != 1 {
                 eprintln!("\n\
                     Unexpected state of WebSocket closer found!\n\
                     \n\
@@ -209,9 +685,28 @@ impl<C: UnderlyingConnection> Closer<C> {
             }
         }
 
-        if !self.0.is_closed().await {
-            if let Err(e) = self.0.send(Message::Close(Some(frame))).await {
-                eprintln!("failed to send a close message: {e}")
+        if let Err(e) = self.writer.lock().await.close_with(frame, &self.__closed__, &self.config).await {
+            return eprintln!("failed to send a close message: {e}")
+        }
+
+        if let crate::HandlerExitClose::Graceful { timeout } = self.config.handler_exit_close {
+            let lent = self.lent.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+            let peer_closed = async {
+                match lent {
+                    Some(reader) => {
+                        let mut reader = reader.lock().await;
+                        while !reader.received_close {
+                            if reader.recv_with_info(&self.writer, &self.__closed__, &self.config).await.is_err() {
+                                break
+                            }
+                        }
+                    }
+                    /* split : received by the `ReadHalf` if it's alive */
+                    None => self.handshake.done().await
+                }
+            };
+            if crate::websocket::with_timeout(timeout, peer_closed).await.is_none() {
+                #[cfg(debug_assertions)] eprintln!("no close frame from the peer within {timeout:?}")
             }
         }
     }
@@ -219,10 +714,10 @@ impl<C: UnderlyingConnection> Closer<C> {
 
 impl<C: UnderlyingConnection> Connection<C> {
     /// create 2 WebSocket connections for
-    /// 
+    ///
     /// 1. used to handle WebSocket session
     /// 2. used to ensure to send a close message (`Cloder`)
-    /// 
+    ///
     /// *example.rs*
     /// ```
     /// # use mews::{Connection, Config, Handler, Message, CloseCode, CloseFrame};
@@ -233,25 +728,82 @@ impl<C: UnderlyingConnection> Connection<C> {
     ///     handler: Handler<tokio::net::TcpStream>,
     /// ) {
     ///     let (conn, closer) = Connection::new(connection, config);
-    /// 
+    ///
     ///     // 1. handle WebSocket session
     ///     handler(conn).await;
-    /// 
+    ///
     ///     // 2. send a close message if not already closed
     ///     closer.send_close_if_not_closed().await;
-    /// 
+    ///
     ///     println!("WebSocket session finished")
     /// }
     /// ```
     pub fn new(conn: C, config: Config) -> (Self, Closer<C>) {
+        let peer_addr = as_tcp_stream(&conn).and_then(|tcp| tcp.peer_addr().ok());
+        if let (Some(tcp), Some(linger)) = (as_tcp_stream(&conn), config.tcp_linger) {
+            if let Err(e) = crate::runtime::with_socket(tcp, |socket| socket.set_linger(linger)) {
+                #[cfg(debug_assertions)] eprintln!("failed to set `SO_LINGER`: {e}")
+            }
+        }
+        if let (Some(tcp), Some(size)) = (as_tcp_stream(&conn), config.socket_recv_buffer) {
+            if let Err(e) = crate::runtime::with_socket(tcp, |socket| socket.set_recv_buffer_size(size)) {
+                #[cfg(debug_assertions)] eprintln!("failed to set `SO_RCVBUF`: {e}")
+            }
+        }
+        if let (Some(tcp), Some(size)) = (as_tcp_stream(&conn), config.socket_send_buffer) {
+            if let Err(e) = crate::runtime::with_socket(tcp, |socket| socket.set_send_buffer_size(size)) {
+                #[cfg(debug_assertions)] eprintln!("failed to set `SO_SNDBUF`: {e}")
+            }
+        }
+
+        #[cfg(feature="__splitref__")]
+        let __conn__ = Arc::new(std::cell::UnsafeCell::new(conn));
+
         #[cfg(feature="__splitref__")]
-        let conn = Arc::new(std::cell::UnsafeCell::new(conn));
+        // SAFETY: `__conn__` is held by everything holding the halves : `Connection`
+        // ( and then `ReadHalf` / `WriteHalf` split from it ), `Closer` and the entries
+        // of `ConnectionManager`, and the halves are dropped before `__conn__` in each of them
+        let (r, w) = unsafe {&mut *__conn__.get()}.split();
+        #[cfg(feature="__clone__")]
+        let (r, w) = conn.split();
 
         let __closed__ = Arc::new(RwLock::new(false));
+        let handshake = Arc::new(Handshake::default());
+        let mut writer = WriteState::new(w);
+        writer.bucket = config.max_send_bytes_per_second.map(TokenBucket::new);
+        writer.handshake = handshake.clone();
+        let writer = Arc::new(Mutex::new(writer));
+        let messages = MessageReader::new();
+        let (received_unmasked, activity) = (messages.received_unmasked().clone(), messages.activity().clone());
+        let reader = Arc::new(Mutex::new(ReadState { conn: r, messages, pending_error: None, received_close: false, pending_pong: None, held: None, handshake: handshake.clone() }));
+        let lent = Arc::new(std::sync::Mutex::new(
+            matches!(config.handler_exit_close, crate::HandlerExitClose::Graceful { .. }).then(|| reader.clone())
+        ));
 
         (
-            Self { conn: conn.clone(), __closed__: __closed__.clone(), config: config.clone(), n_buffered: 0 },
-            Closer(Connection { conn, __closed__, config, n_buffered: 0 })
+            Self {
+                __closed__: __closed__.clone(),
+                received_unmasked,
+                activity,
+                reader,
+                lent:       lent.clone(),
+                handshake:  handshake.clone(),
+                writer:     writer.clone(),
+                config:     config.clone(),
+                protocol:   None,
+                peer_addr,
+                #[cfg(feature="__splitref__")]
+                __conn__:   __conn__.clone(),
+            },
+            Closer {
+                __closed__,
+                writer,
+                lent,
+                config,
+                handshake,
+                #[cfg(feature="__splitref__")]
+                __conn__,
+            }
         )
     }
 
@@ -259,73 +811,451 @@ impl<C: UnderlyingConnection> Connection<C> {
         read_closed(&self.__closed__).await
     }
 
-    pub(crate) async fn close(&mut self) {
-        set_closed(&self.__closed__).await
+    /// Wait until the closing handshake completes, i.e. we've both sent and
+    /// received a close frame, or the connection can't receive anymore
+    /// ( by EOF, or the reading side dropped ).
+    /// 
+    /// **note** : The peer's close frame is received only by receiving,
+    /// e.g. `recv` in another task or branch, or [`shutdown`](Connection::shutdown).
+    pub async fn closed(&self) {
+        self.handshake.done().await
+    }
+
+    /// the address of the client, if the underlying connection is
+    /// `TcpStream` of the selected runtime.
+    /// 
+    /// Displaying the `SocketAddr` brackets an IPv6 address, e.g. `[::1]:8080`.
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.peer_addr
+    }
+
+    /// the subprotocol negotiated in the handshake, if any.
+    /// see [`WebSocketContext::negotiate_protocol`](crate::WebSocketContext::negotiate_protocol).
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// whether an unmasked frame has been received from the client so far,
+    /// accepted by `Config::accept_unmasked_frames`.
+    pub fn received_unmasked_frame(&self) -> bool {
+        self.received_unmasked.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// when the connection was established.
+    pub fn connected_at(&self) -> std::time::Instant {
+        self.activity.connected_at()
+    }
+
+    /// when the last frame was received from the client, or
+    /// [`connected_at`](Connection::connected_at) if none yet.
+    /// 
+    /// Only received frames count, e.g. for reaping idle connections
+    /// whose writes still succeed.
+    pub fn last_activity(&self) -> std::time::Instant {
+        self.activity.last_activity()
+    }
+
+    /// the attributes of the connection decided in the handshake, all in one.
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr: self.peer_addr,
+            protocol:  self.protocol.clone(),
+        }
+    }
+
+    /// the settings in effect for the connection : the `Config` it runs with
+    /// and what's negotiated in the handshake.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::Connection;
+    /// #
+    /// fn debug(conn: &Connection) {
+    ///     let effective = conn.effective_config();
+    ///     println!("protocol: {:?}, max message: {:?}", effective.protocol, effective.config.max_message_size);
+    /// }
+    /// ```
+    pub fn effective_config(&self) -> EffectiveConfig {
+        EffectiveConfig {
+            config:   self.config.clone(),
+            protocol: self.protocol.clone(),
+        }
+    }
+}
+
+/// Anything received by [`Connection::recv_any`]
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// a data message ( `Text`, `Binary` or `Reserved` )
+    Message(Message),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+/// Attributes of a connection decided in the handshake, passed to the handler
+/// of [`WebSocketContext::on_upgrade_with_info`](crate::WebSocketContext::on_upgrade_with_info).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// see [`Connection::peer_addr`]
+    pub peer_addr: Option<std::net::SocketAddr>,
+    /// see [`Connection::protocol`]
+    pub protocol:  Option<String>,
+}
+
+/// Settings in effect for a connection, see [`Connection::effective_config`].
+/// 
+/// **note** : mews negotiates no extension ( e.g. `permessage-deflate` isn't
+/// supported ), so the subprotocol is the only negotiated setting, and `config`
+/// is the one passed to the `WebSocketContext` as it is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectiveConfig {
+    pub config:   Config,
+    /// see [`Connection::protocol`]
+    pub protocol: Option<String>,
+}
+
+/// Error of sending to / flushing a connection after it's closed by a close
+/// frame, carried by an `ErrorKind::ConnectionReset` error.
+/// 
+/// This is returned before touching the underlying connection, so it's the
+/// same regardless of the runtime or the socket's state.
+/// 
+/// ```
+/// # fn report(e: std::io::Error/* returned from `send` */) {
+/// if mews::AlreadyClosed::from_error(&e).is_some() {
+///     eprintln!("tried to send after closing")
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AlreadyClosed;
+impl AlreadyClosed {
+    pub fn from_error(error: &Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
     }
 }
+const _: () = {
+    impl std::fmt::Display for AlreadyClosed {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("WebSocket connection is already closed")
+        }
+    }
+    impl std::error::Error for AlreadyClosed {}
+};
 
 impl<C: UnderlyingConnection> Connection<C> {
     /// Await a message from the client and recieve it.
-    /// 
+    ///
     /// **note** : This automatically consumes a `Ping` message and responds with
     /// a corresponded `Pong` message, and then returns `Ok(None)`.
+    ///
+    /// After sending a close frame, this still awaits the client's close frame
+    /// replying to it ( see `Config::discard_after_close` ).
+    ///
+    /// When the client closes the connection without a close frame, this fails
+    /// with an `ErrorKind::UnexpectedEof` error of `CloseCode::Abnormal` ( 1006 ).
+    #[inline]
+    pub async fn recv(&self) -> Result<Option<Message>, Error> {
+        self.recv_with_info().await
+            .map(|message| message.map(|(message, _)| message))
+    }
+
+    /// Same as [`recv`](Connection::recv), but also returns the `RecvInfo`
+    /// (e.g. number of fragments) of the received message.
     #[inline]
-    pub async fn recv(&mut self) -> Result<Option<Message>, Error> {
-        let (conn, config, _) = to_checked_parts(self).await?;
+    pub async fn recv_with_info(&self) -> Result<Option<(Message, RecvInfo)>, Error> {
+        let mut reader = self.reader.lock().await;
 
-        match Message::read_from(conn, config).await? {
-            Some(Message::Ping(payload)) => {
-                self.send(Message::Pong(payload.clone())).await?;
+        let received = match reader.held.take() {
+            Some(held) => Some(held),
+            None => reader.recv_with_info(&self.writer, &self.__closed__, &self.config).await?
+        };
+        match received {
+            Some((Message::Ping(payload), _)) => {
+                /* the next `Ping` already buffered replaces this `Pong` */
+                reader.pending_pong = Some(payload);
+                if !(self.config.coalesce_pongs && reader.messages.has_buffered_frame(&self.config)) {
+                    self.send_pending_pong(&mut reader).await?;
+                }
                 Ok(None)
             }
-            other => Ok(other)
+            Some(received) => {
+                reader.held = Some(received);
+                self.send_pending_pong(&mut reader).await?;
+                Ok(reader.held.take())
+            }
+            None => Ok(None)
         }
     }
 
-    /// Send a message to the client.
+    /// Await any frame-level event from the client, including control frames.
     /// 
-    /// **note** : When sending a `Close` message, this automatically close the
-    /// connection, then the connection is not available anymore.
-    #[inline]
-    pub async fn send(&mut self, message: impl Into<Message>) -> Result<(), Error> {
-        let message = message.into();
+    /// In contrast to `recv`, this doesn't answer a `Ping` automatically :
+    /// it's up to the caller to send the `Pong`.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{Connection, Message, connection::Event};
+    /// #
+    /// async fn handler(conn: Connection) -> std::io::Result<()> {
+    ///     loop {
+    ///         match conn.recv_any().await? {
+    ///             Event::Ping(payload) => conn.send(Message::Pong(payload)).await?,
+    ///             Event::Pong(_) => (),
+    ///             Event::Close(_) => return Ok(()),
+    ///             Event::Message(message) => println!("{message:?}"),
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub async fn recv_any(&self) -> Result<Event, Error> {
+        let mut reader = self.reader.lock().await;
+        let (message, _) = match reader.held.take() {
+            Some(held) => held,
+            None => reader.recv_with_info(&self.writer, &self.__closed__, &self.config).await?
+                .ok_or_else(CloseFrame::abnormal)?
+        };
+        Ok(match message {
+            Message::Ping(payload) => Event::Ping(payload),
+            Message::Pong(payload) => Event::Pong(payload),
+            Message::Close(frame)  => Event::Close(frame),
+            message => Event::Message(message),
+        })
+    }
 
-        let (conn, config, n_buffered) = to_checked_parts(self).await?;
+    /// Send the `Pong` held back by `Config::coalesce_pongs`, if any.
+    /// 
+    /// This is cancel-safe : the payload is kept until it's buffered to be sent.
+    async fn send_pending_pong(&self, reader: &mut ReadState<ReadHalfOf<C>>) -> Result<(), Error> {
+        if reader.pending_pong.is_none() {
+            return Ok(())
+        }
+        if self.is_closed().await {
+            reader.pending_pong = None;
+            return Ok(())
+        }
+        let mut writer = self.writer.lock().await;
+        writer.check_open(&self.__closed__).await?;
+        let payload = reader.pending_pong.take().unwrap();
+        Frame::encode_unmasked_parts(true, OpCode::Pong, &payload, &mut writer.buffer);
+        reader.messages.recycle(payload);
+        writer.drain(&self.__closed__).await
+    }
 
-        let closing = matches!(message, Message::Close(_));
-        send(message, conn, config, n_buffered).await?;
-        if closing {self.close().await}
+    /// Same as [`recv`](Connection::recv), but fails with an error of
+    /// `ErrorKind::TimedOut` if no message arrives within `timeout`.
+    ///
+    /// A timeout doesn't corrupt the stream : a partially-received frame or
+    /// fragmented message is kept, and the next `recv` resumes it, so the
+    /// connection is still usable after a timeout.
+    pub async fn recv_timeout(&self, timeout: std::time::Duration) -> Result<Option<Message>, Error> {
+        crate::websocket::with_timeout(timeout, self.recv()).await
+            .unwrap_or_else(|| Err(Error::new(std::io::ErrorKind::TimedOut, "No message arrived within the timeout")))
+    }
 
-        Ok(())
+    /// Send a message to the client.
+    ///
+    /// This resolves once the message, after any messages buffered by `write`,
+    /// is written and flushed to the connection. The write side is held
+    /// throughout, so no other task's message slips in between : there's no
+    /// need of `flush` after this.
+    ///
+    /// **note** : When sending a `Close` message, this automatically close the
+    /// connection, then the connection is not available anymore.
+    #[inline]
+    pub async fn send(&self, message: impl Into<Message>) -> Result<(), Error> {
+        let mut writer = self.writer.lock().await;
+        writer.send(message.into(), &self.__closed__, &self.config).await
     }
 
     /// Write a message to the connection. Buffering behavior is customizable
     /// via [`WebSocketContext::with(Config)`](crate::WebSocketContext::with).
-    /// 
+    ///
     /// **note** : When sending a `Close` message, this automatically close the
     /// connection, then the connection is not available anymore.
-    pub async fn write(&mut self, message: impl Into<Message>) -> Result<usize, Error> {
-        let message = message.into();
+    pub async fn write(&self, message: impl Into<Message>) -> Result<usize, Error> {
+        let mut writer = self.writer.lock().await;
+        writer.write(message.into(), &self.__closed__, &self.config).await
+    }
 
-        let (conn, config, n_buffered) = to_checked_parts(self).await?;
+    /// Flush the connection explicitly.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let mut writer = self.writer.lock().await;
+        writer.flush(&self.__closed__).await
+    }
 
-        let closing = matches!(message, Message::Close(_));
-        let n = write(message, conn, config, n_buffered).await?;
-        if closing {self.close().await}
+    /// Close the connection with `code` and no reason.
+    /// 
+    /// `code` can be of an application's own type convertible into `CloseCode` :
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{Connection, CloseCode};
+    /// #
+    /// enum AppClose { Kicked, Expired }
+    /// 
+    /// impl From<AppClose> for CloseCode {
+    ///     fn from(close: AppClose) -> Self {
+    ///         match close {
+    ///             AppClose::Kicked  => CloseCode::Library(4000),
+    ///             AppClose::Expired => CloseCode::Library(4001),
+    ///         }
+    ///     }
+    /// }
+    /// 
+    /// async fn kick(conn: &Connection) -> std::io::Result<()> {
+    ///     conn.close_code(AppClose::Kicked).await
+    /// }
+    /// ```
+    #[inline]
+    pub async fn close_code(&self, code: impl Into<CloseCode>) -> Result<(), Error> {
+        self.send(Message::Close(Some(code.into().into()))).await
+    }
 
-        Ok(n)
+    /// Close the connection with `CloseCode::Protocol` ( 1002 ) and `reason`
+    /// ( truncated to `CloseFrame::MAX_REASON_SIZE` ) on an application-level
+    /// protocol violation.
+    /// 
+    /// returns the error to report it, carrying the sent `CloseFrame` just like
+    /// the ones the connection closed by itself on ( see [`CloseFrame::from_error`] ),
+    /// or the error in sending it.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{Connection, Message};
+    /// #
+    /// async fn handler(conn: Connection) -> std::io::Result<()> {
+    ///     while let Some(message) = conn.recv().await? {
+    ///         let Message::Text(command) = message else {
+    ///             return Err(conn.protocol_error("expected a text command").await)
+    ///         };
+    ///         conn.send(command).await?;
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn protocol_error(&self, reason: impl Into<String>) -> Error {
+        let error = CloseFrame::error(CloseCode::Protocol, reason);
+        let frame = CloseFrame::from_error(&error).cloned().unwrap();
+        match self.send(Message::Close(Some(frame))).await {
+            Ok(()) => error,
+            Err(e) => e
+        }
     }
 
-    /// Flush the connection explicitly.
-    pub async fn flush(&mut self) -> Result<(), Error> {
-        let (conn, _, n_buffered) = to_checked_parts(self).await?;
-        flush(conn, n_buffered).await
+    /// Close the connection gracefully : send a close frame of `Config::default_close`
+    /// (`CloseCode::Normal` by default), and then wait for the client's close frame
+    /// replying to it, discarding any other message, within [`SHUTDOWN_TIMEOUT`].
+    /// 
+    /// returns the client's close frame ( `None` if it has no status, or it's already
+    /// received by `recv` ), or fails with `ErrorKind::TimedOut` if no reply arrives.
+    /// 
+    /// When the client starts closing at the same time, its close frame crossing
+    /// ours is taken as the reply, completing the closing handshake on both sides.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{Connection, Message};
+    /// #
+    /// async fn handler(conn: Connection) {
+    ///     while let Ok(Some(Message::Text(text))) = conn.recv().await {
+    ///         if text == "bye" {
+    ///             break
+    ///         }
+    ///     }
+    /// 
+    ///     match conn.shutdown().await {
+    ///         Ok(reply) => println!("closed by client with {reply:?}"),
+    ///         Err(e) => eprintln!("no graceful close: {e}"),
+    ///     }
+    /// }
+    /// ```
+    pub async fn shutdown(&self) -> Result<Option<CloseFrame>, Error> {
+        self.shutdown_with(default_close(&self.config), SHUTDOWN_TIMEOUT).await
+    }
+
+    /// Same as [`shutdown`](Connection::shutdown), but with the close frame and timeout.
+    pub async fn shutdown_with(&self, frame: CloseFrame, timeout: std::time::Duration) -> Result<Option<CloseFrame>, Error> {
+        self.writer.lock().await.close_with(frame, &self.__closed__, &self.config).await?;
+
+        let mut reader = self.reader.lock().await;
+        if reader.received_close {
+            return Ok(None)
+        }
+        crate::websocket::with_timeout(timeout, async {
+            loop {
+                if let Some((Message::Close(frame), _)) = reader.recv_with_info(&self.writer, &self.__closed__, &self.config).await? {
+                    return Ok(frame)
+                }
+            }
+        }).await
+            .unwrap_or_else(|| Err(Error::new(std::io::ErrorKind::TimedOut, "No close frame arrived within the timeout")))
+    }
+
+    /// Forward incoming frames to `other` as they are, preserving opcodes,
+    /// fragmentation and control frames, until a close frame is forwarded
+    /// or the client disconnects.
+    /// 
+    /// Each frame is sent to `other` as by its `send` : validated by its `Config`
+    /// ( e.g. refragmented by its `max_frame_size`, a close frame rewritten by its
+    /// `on_close_send` ), and a forwarded close frame closes `other`. This fails
+    /// as already closed once `other` is closed.
+    /// 
+    /// **note** : Fragmented messages are not reassembled, so `max_message_size`
+    /// is not checked while `max_frame_size` is.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::Connection;
+    /// # use std::sync::Arc;
+    /// #
+    /// async fn proxy(a: Connection, b: Connection) {
+    ///     let (a, b) = (Arc::new(a), Arc::new(b));
+    ///
+    ///     let b_to_a = tokio::spawn({
+    ///         let (a, b) = (a.clone(), b.clone());
+    ///         async move {b.forward_to(&a).await}
+    ///     });
+    ///     let a_to_b = a.forward_to(&b).await;
+    ///
+    ///     if let Err(e) = a_to_b.and(b_to_a.await.unwrap()) {
+    ///         eprintln!("proxy failed: {e}")
+    ///     }
+    /// }
+    /// ```
+    pub async fn forward_to<C2: UnderlyingConnection>(&self, other: &Connection<C2>) -> Result<(), Error> {
+        let mut reader = self.reader.lock().await;
+        let ReadState { conn, messages, .. } = &mut *reader;
+
+        loop {
+            /* reading on after we closed is fine to forward the client's reply to the close */
+            let frame = match messages.read_frame(conn, &self.config).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    if let Some(frame) = CloseFrame::from_error(&e) {
+                        let mut writer = self.writer.lock().await;
+                        if let Err(e) = writer.close_with(frame.clone(), &self.__closed__, &self.config).await {
+                            eprintln!("failed to send a close message: {e}")
+                        }
+                    }
+                    return Err(e)
+                }
+            };
+
+            let closing = frame.opcode == OpCode::Close;
+            other.writer.lock().await.send_frame(frame, &other.__closed__, &other.config).await?;
+            if closing {
+                return Ok(())
+            }
+        }
     }
 }
 
 pub mod split {
     use super::*;
-    
+
     #[cfg(feature="__splitref__")]
     pub trait Splitable<'split>: Read + Write + Unpin + Sized {
         type ReadHalf: Read + Unpin;
@@ -377,105 +1307,2088 @@ pub mod split {
         }
     };
 
-    pub struct ReadHalf<C: Read + Unpin = <crate::runtime::TcpStream as Splitable<'static>>::ReadHalf> {
+    /// receiving / sending started by a poll method, owning what it works on
+    /// to be resumed by the next poll
+    type InFlight<T> = std::pin::Pin<Box<dyn std::future::Future<Output = T>>>;
+    /// result of `ReadHalf::recv_with_info`
+    type Received = Result<Option<(Message, RecvInfo)>, Error>;
+
+    const _: (/* trait impls */) = {
+        // SAFETY: the same as `Connection` they're split from : the futures
+        // in flight hold only its states, and are touched only by `&mut self`
+        unsafe impl<C: UnderlyingConnection> Send for ReadHalf<C> {}
+        unsafe impl<C: UnderlyingConnection> Sync for ReadHalf<C> {}
+        unsafe impl<C: UnderlyingConnection> Send for WriteHalf<C> {}
+        unsafe impl<C: UnderlyingConnection> Sync for WriteHalf<C> {}
+    };
+
+    pub struct ReadHalf<C: UnderlyingConnection = crate::runtime::TcpStream> {
         __closed__: Arc<RwLock<bool>>,
-        conn:   C,
+        /// `None` while it's taken by `receiving`
+        state:  Option<ReadState<ReadHalfOf<C>>>,
+        /// `recv` started by `poll_recv` and not completed yet
+        receiving: Option<InFlight<(ReadState<ReadHalfOf<C>>, Received)>>,
+        /// shared with `WriteHalf` to send a close frame on protocol errors
+        writer: Arc<Mutex<WriteState<WriteHalfOf<C>>>>,
         config: Config,
+        /// what's met by `recv_batch` after some messages or ended `drain_inbound`,
+        /// for the next receive
+        deferred: Option<Result<Option<(Message, RecvInfo)>, Error>>,
+        received_unmasked: Arc<std::sync::atomic::AtomicBool>,
+        activity:          Arc<crate::frame::Activity>,
+
+        /// keeps the underlying connection alive for `state` and `writer` (dropped last)
+        #[cfg(feature="__splitref__")]
+        __conn__: Arc<std::cell::UnsafeCell<C>>,
     }
-    impl<C: Read + Unpin> ReadHalf<C> {
+    impl<C: UnderlyingConnection> ReadHalf<C> {
+        /// the read state, which is always back after a receive completed
+        fn state(&mut self) -> &mut ReadState<ReadHalfOf<C>> {
+            self.state.as_mut().expect("the read state is taken by `poll_recv` in flight")
+        }
+
+        /// Poll the `recv` in flight of `poll_recv` if any, taking the state back
+        /// once it completes. This keeps it in place, so it's cancel-safe.
+        fn poll_receiving(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Received>> {
+            let Some(receiving) = &mut self.receiving else {
+                return std::task::Poll::Ready(None)
+            };
+            let (state, received) = std::task::ready!(receiving.as_mut().poll(cx));
+            (self.state, self.receiving) = (Some(state), None);
+            std::task::Poll::Ready(Some(received))
+        }
+
         /// Await a message from the client and recieve it.
-        /// 
+        ///
         /// **note** : This doesn't automatically handle `Ping` message
         /// (in contrast to `Connection::recv`).
         #[inline]
         pub async fn recv(&mut self) -> Result<Option<Message>, Error> {
-            let Self { __closed__, conn, config } = self;
-            let conn = underlying!(__closed__, conn).await?;
-            Message::read_from(conn, config).await
+            self.recv_with_info().await
+                .map(|message| message.map(|(message, _)| message))
+        }
+
+        /// Same as [`recv`](ReadHalf::recv), but also returns the `RecvInfo`
+        /// (e.g. number of fragments) of the received message.
+        #[inline]
+        pub async fn recv_with_info(&mut self) -> Result<Option<(Message, RecvInfo)>, Error> {
+            if let Some(deferred) = self.deferred.take() {
+                return deferred
+            }
+            if let Some(received) = std::future::poll_fn(|cx| self.poll_receiving(cx)).await {
+                return received
+            }
+            let Self { state, writer, __closed__, config, .. } = self;
+            let state = state.as_mut().expect("the read state is taken by `poll_recv` in flight");
+            state.recv_with_info(writer, __closed__, config).await
+        }
+
+        /// Receive up to `max` data messages at once : after the first one,
+        /// this takes only the ones already buffered without waiting, or with
+        /// `deadline`, waits for more until it. ( `Config::read_ahead` lets more
+        /// messages be buffered at once. )
+        /// 
+        /// `Ping`s are answered with `Pong`s and `Pong`s are skipped, out of the
+        /// batch. A `Close` message ends the batch as its last one.
+        /// 
+        /// An error after some messages is returned by the next receive,
+        /// after the batch.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::ReadHalf;
+        /// # use std::time::{Duration, Instant};
+        /// #
+        /// async fn process(mut r: ReadHalf) -> std::io::Result<()> {
+        ///     loop {
+        ///         let deadline = Instant::now() + Duration::from_millis(10);
+        ///         let batch = r.recv_batch(64, Some(deadline)).await?;
+        ///         /* process the batch ... */
+        /// #       if batch.is_empty() {return Ok(())}
+        ///     }
+        /// }
+        /// ```
+        pub async fn recv_batch(&mut self, max: usize, deadline: Option<std::time::Instant>) -> Result<Vec<Message>, Error> {
+            let mut batch = Vec::new();
+            while batch.len() < max {
+                let received = match deadline {
+                    Some(deadline) => {
+                        let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+                        match crate::websocket::with_timeout(timeout, self.recv()).await {
+                            Some(received) => received,
+                            None => break
+                        }
+                    }
+                    None => {
+                        if !batch.is_empty() && !self.state.as_ref().is_some_and(|state| state.messages.has_buffered_frame(&self.config)) {
+                            break
+                        }
+                        self.recv().await
+                    }
+                };
+
+                match received {
+                    Ok(Some(Message::Ping(payload))) => self.pong(payload).await?,
+                    Ok(None | Some(Message::Pong(_))) => continue,
+                    Ok(Some(close @ Message::Close(_))) => {
+                        batch.push(close);
+                        break
+                    }
+                    Ok(Some(message)) => batch.push(message),
+                    Err(e) if batch.is_empty() => return Err(e),
+                    Err(e) => {
+                        self.deferred = Some(Err(e));
+                        break
+                    }
+                }
+            }
+            Ok(batch)
+        }
+
+        /// answer a `Ping` unless we've closed the connection
+        async fn pong(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+            if read_closed(&self.__closed__).await {
+                return Ok(())
+            }
+            let sent = self.writer.lock().await.send_pong(&payload, &self.__closed__).await;
+            self.state().messages.recycle(payload);
+            sent
+        }
+
+        /// whether an unmasked frame has been received from the client so far,
+        /// accepted by `Config::accept_unmasked_frames`.
+        pub fn received_unmasked_frame(&self) -> bool {
+            self.received_unmasked.load(std::sync::atomic::Ordering::Relaxed)
+        }
+
+        /// when the connection was established.
+        pub fn connected_at(&self) -> std::time::Instant {
+            self.activity.connected_at()
+        }
+
+        /// when the last frame was received from the client, see [`Connection::last_activity`].
+        pub fn last_activity(&self) -> std::time::Instant {
+            self.activity.last_activity()
+        }
+
+        /// Same as [`recv_deadline`](ReadHalf::recv_deadline) with the deadline
+        /// `timeout` after now.
+        #[inline]
+        pub async fn recv_timeout(&mut self, timeout: std::time::Duration) -> Result<Option<Message>, Error> {
+            self.recv_deadline(std::time::Instant::now() + timeout).await
+        }
+
+        /// Await a message until the `deadline`, independently of any other timeout.
+        /// 
+        /// returns an error of `ErrorKind::TimedOut` if no message arrives by the deadline.
+        /// A partially-received message is kept and resumed by the next `recv`.
+        pub async fn recv_deadline(&mut self, deadline: std::time::Instant) -> Result<Option<Message>, Error> {
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            crate::websocket::with_timeout(timeout, self.recv()).await
+                .unwrap_or_else(|| Err(Error::new(std::io::ErrorKind::TimedOut, "No message arrived by the deadline")))
+        }
+
+        /// Close the connection with `frame`, sent through the write side shared
+        /// with the `WriteHalf`, so the reading task can initiate shutdown.
+        /// 
+        /// After this, sending by the `WriteHalf` fails as already closed.
+        /// This does nothing if the connection is already closed.
+        pub async fn request_close(&mut self, frame: CloseFrame) -> Result<(), Error> {
+            let mut writer = self.writer.lock().await;
+            writer.close_with(frame, &self.__closed__, &self.config).await
+        }
+
+        /// Poll version of [`recv`](ReadHalf::recv) to drive the connection
+        /// from a manual `Future::poll`.
+        /// 
+        /// The receiving started by this is kept on `Poll::Pending`, to be
+        /// resumed by the next `poll_recv` ( or `recv` ), and wakes the task
+        /// when it can make progress.
+        pub fn poll_recv(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<Option<Message>, Error>> {
+            if let Some(deferred) = self.deferred.take() {
+                return std::task::Poll::Ready(deferred.map(|message| message.map(|(message, _)| message)))
+            }
+            if self.receiving.is_none() {
+                let mut state = self.state.take().expect("the read state is taken by `poll_recv` in flight");
+                let (writer, __closed__, config) = (self.writer.clone(), self.__closed__.clone(), self.config.clone());
+                self.receiving = Some(Box::pin(async move {
+                    let received = state.recv_with_info(&writer, &__closed__, &config).await;
+                    (state, received)
+                }));
+            }
+            self.poll_receiving(cx).map(|received| received
+                .expect("`receiving` is just set")
+                .map(|message| message.map(|(message, _)| message)))
+        }
+
+        /// Read and discard the messages immediately available, e.g. trailing
+        /// frames of an abandoned request, returning the number of discarded
+        /// data messages.
+        /// 
+        /// This never waits for more frames : it stops once receiving would block.
+        /// `Ping`s are still answered with `Pong`s, and a `Close` message ends
+        /// the draining to be returned by the next `recv` instead of discarded.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::ReadHalf;
+        /// #
+        /// async fn abandon(r: &mut ReadHalf) -> std::io::Result<()> {
+        ///     let discarded = r.drain_inbound().await?;
+        ///     println!("discarded {discarded} messages");
+        ///     Ok(())
+        /// }
+        /// ```
+        pub async fn drain_inbound(&mut self) -> Result<usize, Error> {
+            use std::future::Future;
+
+            let mut discarded = 0;
+            loop {
+                /* `recv_with_info` is cancel-safe, so it's polled just once each */
+                let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+                let std::task::Poll::Ready(received) = std::pin::pin!(self.recv_with_info()).as_mut().poll(&mut cx) else {
+                    return Ok(discarded)
+                };
+                match received? {
+                    Some((Message::Ping(payload), _)) => self.pong(payload).await?,
+                    None | Some((Message::Pong(_), _)) => continue,
+                    Some(close @ (Message::Close(_), _)) => {
+                        self.deferred = Some(Ok(Some(close)));
+                        return Ok(discarded)
+                    }
+                    Some(_) => discarded += 1,
+                }
+            }
+        }
+
+        /// Transform each incoming data message by `f`.
+        /// 
+        /// Control frames are handled internally by the returned [`Map`] :
+        /// `Ping` is answered with `Pong`, `Pong` is skipped, and `Close`
+        /// ends the messages.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::{Connection, Message};
+        /// #
+        /// async fn handler(conn: Connection) {
+        ///     let (r, _w) = conn.split();
+        ///     let mut lengths = r.map(|message| match message {
+        ///         Message::Text(text)  => text.len(),
+        ///         Message::Binary(bin) => bin.len(),
+        ///         _ => 0,
+        ///     });
+        ///     while let Ok(Some(len)) = lengths.recv().await {
+        ///         println!("received {len} bytes");
+        ///     }
+        /// }
+        /// ```
+        pub fn map<T, F: FnMut(Message) -> T>(self, f: F) -> Map<C, F> {
+            Map { half: self, f }
+        }
+
+        /// Write the payload of each binary message to `writer` until the
+        /// connection is closed by a close frame, returning the number of bytes.
+        /// 
+        /// `Ping`s are answered along the way, and a text message fails with
+        /// an `ErrorKind::InvalidData` error.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::ReadHalf;
+        /// #
+        /// async fn bridge(mut r: ReadHalf, stdin: &mut (impl tokio::io::AsyncWrite + Unpin)) -> std::io::Result<()> {
+        ///     let n = r.pipe_to(stdin).await?;
+        ///     println!("piped {n} bytes");
+        ///     Ok(())
+        /// }
+        /// ```
+        pub async fn pipe_to(&mut self, writer: &mut (impl Write + Unpin)) -> Result<u64, Error> {
+            let mut n = 0;
+            loop {
+                match self.recv().await? {
+                    Some(Message::Binary(payload)) => {
+                        writer.write_all(&payload).await?;
+                        n += payload.len() as u64;
+                    }
+                    Some(Message::Ping(payload)) => self.pong(payload).await?,
+                    Some(Message::Text(_)) => return Err(Error::new(
+                        std::io::ErrorKind::InvalidData, "Expected binary message to pipe")),
+                    Some(Message::Pong(_) | Message::Reserved { .. }) => continue,
+                    None | Some(Message::Close(_)) => break,
+                }
+            }
+            writer.flush().await?;
+            Ok(n)
+        }
+    }
+
+    /// `ReadHalf` transforming each data message, created by [`ReadHalf::map`].
+    pub struct Map<C: UnderlyingConnection, F> {
+        half: ReadHalf<C>,
+        f:    F,
+    }
+    impl<C: UnderlyingConnection, T, F: FnMut(Message) -> T> Map<C, F> {
+        /// Await the next data message and return it transformed.
+        /// 
+        /// returns `Ok(None)` when the connection is closed by a close frame.
+        pub async fn recv(&mut self) -> Result<Option<T>, Error> {
+            loop {
+                match self.half.recv().await? {
+                    None | Some(Message::Close(_)) => return Ok(None),
+                    Some(Message::Ping(payload)) => self.half.pong(payload).await?,
+                    Some(Message::Pong(_)) => continue,
+                    Some(message) => return Ok(Some((self.f)(message))),
+                }
+            }
+        }
+
+        /// Unwrap the original `ReadHalf`.
+        pub fn into_inner(self) -> ReadHalf<C> {
+            self.half
         }
     }
 
-    pub struct WriteHalf<C: Write + Unpin = <crate::runtime::TcpStream as Splitable<'static>>::WriteHalf> {
+    pub struct WriteHalf<C: UnderlyingConnection = crate::runtime::TcpStream> {
         __closed__: Arc<RwLock<bool>>,
-        conn:       C,
-        config:     Config,
-        n_buffered: usize,
+        state:  Arc<Mutex<WriteState<WriteHalfOf<C>>>>,
+        config: Config,
+        /// sending started by `poll_send` / `poll_send_ready` and not completed yet
+        sending: Option<InFlight<Result<(), Error>>>,
+
+        /// keeps the underlying connection alive for `state` (dropped last)
+        #[cfg(feature="__splitref__")]
+        __conn__: Arc<std::cell::UnsafeCell<C>>,
     }
-    impl<C: Write + Unpin> WriteHalf<C> {
-        /// Send a message to the client.
+    impl<C: UnderlyingConnection> WriteHalf<C> {
+        /// Poll to complete sending all the messages passed to
+        /// [`poll_send`](WriteHalf::poll_send), and flush the connection.
+        /// 
+        /// The sending is kept on `Poll::Pending`, to be resumed by the next
+        /// poll, and wakes the task when it can make progress.
+        pub fn poll_send_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Error>> {
+            let sending = self.sending.get_or_insert_with(|| {
+                let (state, __closed__) = (self.state.clone(), self.__closed__.clone());
+                Box::pin(async move {state.lock().await.drain(&__closed__).await})
+            });
+            let sent = std::task::ready!(sending.as_mut().poll(cx));
+            self.sending = None;
+            std::task::Poll::Ready(sent)
+        }
+
+        /// Poll version of [`send`](WriteHalf::send) to drive the connection
+        /// from a manual `Future::poll`.
         /// 
+        /// The message is always taken : on `Poll::Pending`, the rest of the
+        /// sending is done by [`poll_send_ready`](WriteHalf::poll_send_ready),
+        /// which should be polled until ready before the next `poll_send`.
+        /// ( Otherwise, the next message is sent after this one. )
+        pub fn poll_send(&mut self, cx: &mut std::task::Context<'_>, message: impl Into<Message>) -> std::task::Poll<Result<(), Error>> {
+            let (message, previous) = (message.into(), self.sending.take());
+            let (state, __closed__, config) = (self.state.clone(), self.__closed__.clone(), self.config.clone());
+            self.sending = Some(Box::pin(async move {
+                if let Some(previous) = previous {
+                    previous.await?
+                }
+                state.lock().await.send(message, &__closed__, &config).await
+            }));
+            self.poll_send_ready(cx)
+        }
+
+        /// Send a message to the client, resolving once it's written and flushed
+        /// to the connection, the same as [`Connection::send`].
+        ///
         /// **note** : When sending a `Close` message, this automatically close the
         /// connection, then the connection is not available anymore.
         #[inline]
         pub async fn send(&mut self, message: impl Into<Message>) -> Result<(), Error> {
-            let message = message.into();
-
-            let Self { __closed__, conn, config, n_buffered } = self;
-            let conn = underlying!(__closed__, conn).await?;
-
-            let closing = matches!(message, Message::Close(_));
-            send(message, conn, config, n_buffered).await?;
-            if closing {set_closed(__closed__).await}
-
-            Ok(())
+            let mut state = self.state.lock().await;
+            state.send(message.into(), &self.__closed__, &self.config).await
         }
 
         /// Write a message to the connection. Buffering behavior is customizable
         /// via [`WebSocketContext::with(Config)`](crate::WebSocketContext::with).
-        /// 
+        ///
         /// **note** : When sending a `Close` message, this automatically close the
         /// connection, then the connection is not available anymore.
         pub async fn write(&mut self, message: impl Into<Message>) -> Result<usize, Error> {
-            let message = message.into();
+            let mut state = self.state.lock().await;
+            state.write(message.into(), &self.__closed__, &self.config).await
+        }
+
+        /// Flush the connection explicitly.
+        pub async fn flush(&mut self) -> Result<(), Error> {
+            let mut state = self.state.lock().await;
+            state.flush(&self.__closed__).await
+        }
+
+        /// Number of bytes written by `write` ( or left by a cancelled send )
+        /// but not flushed to the connection yet.
+        /// 
+        /// This is for application-level backpressure : e.g. producing more
+        /// data only while it's under `Config::write_buffer_size`.
+        pub async fn pending_write_bytes(&self) -> usize {
+            self.state.lock().await.n_buffered()
+        }
 
-            let Self { __closed__, conn, config, n_buffered } = self;
-            let conn = underlying!(__closed__, conn).await?;
+        /// Send the chunks from `source` as one text message, fragmented
+        /// into continuation frames, without building the whole `String`.
+        /// 
+        /// **note** : Each chunk must be a whole `String` itself, so that the
+        /// message is valid UTF-8 : never split a multi-byte character across chunks.
+        /// When this is cancelled in the middle, the message is finished by
+        /// an empty final fragment just before the next data message.
+        #[inline]
+        pub async fn send_text_stream(&mut self, source: impl futures_core::Stream<Item = String>) -> Result<(), Error> {
+            self.send_text_stream_with(source, FlushPolicy::Each).await
+        }
 
-            let closing = matches!(message, Message::Close(_));
-            let n = write(message, conn, config, n_buffered).await?;
-            if closing {set_closed(__closed__).await}
+        /// Same as [`send_text_stream`](WriteHalf::send_text_stream), but flushes
+        /// the chunks by `flush` instead of each one.
+        pub async fn send_text_stream_with(&mut self, source: impl futures_core::Stream<Item = String>, flush: FlushPolicy) -> Result<(), Error> {
+            struct Bytes<S>(S);
+            impl<S: futures_core::Stream<Item = String>> futures_core::Stream for Bytes<S> {
+                type Item = Vec<u8>;
+                fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Vec<u8>>> {
+                    // SAFETY: `self.0` is never moved out
+                    unsafe {self.map_unchecked_mut(|s| &mut s.0)}.poll_next(cx).map(|s| s.map(String::into_bytes))
+                }
+            }
 
-            Ok(n)
+            let mut state = self.state.lock().await;
+            state.send_stream(OpCode::Text, Bytes(source), flush, &self.__closed__, &self.config).await
         }
 
-        /// Flush the connection explicitly.
-        pub async fn flush(&mut self) -> Result<(), Error> {
-            let Self { __closed__, conn, n_buffered, config:_ } = self;
-            let conn = underlying!(__closed__, conn).await?;
+        /// Start a text message to be sent piece by piece with [`TextWriter`].
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::WriteHalf;
+        /// #
+        /// async fn stream_tokens(w: &mut WriteHalf, tokens: &[&str]) -> std::io::Result<()> {
+        ///     let mut text = w.start_text();
+        ///     for token in tokens {
+        ///         text.write(token).await?;
+        ///     }
+        ///     text.finish().await
+        /// }
+        /// ```
+        #[inline]
+        pub fn start_text(&mut self) -> TextWriter<'_, C> {
+            self.start_text_with(FlushPolicy::Each)
+        }
+
+        /// Same as [`start_text`](WriteHalf::start_text), but the `TextWriter`
+        /// flushes the pieces by `flush` instead of each one.
+        pub fn start_text_with(&mut self, flush: FlushPolicy) -> TextWriter<'_, C> {
+            TextWriter { half: self, flush, written: 0 }
+        }
+
+        /// Send the bytes read from `reader` until its EOF, each chunk as
+        /// a binary message, returning the number of bytes.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::WriteHalf;
+        /// #
+        /// async fn bridge(mut w: WriteHalf, stdout: &mut (impl tokio::io::AsyncRead + Unpin)) -> std::io::Result<()> {
+        ///     w.pipe_from(stdout).await?;
+        ///     w.close_code(mews::CloseCode::Normal).await
+        /// }
+        /// ```
+        pub async fn pipe_from(&mut self, reader: &mut (impl Read + Unpin)) -> Result<u64, Error> {
+            const CHUNK_SIZE: usize = 16 * 1024;
+
+            let (mut buf, mut n) = (vec![0; CHUNK_SIZE], 0);
+            loop {
+                match reader.read(&mut buf).await? {
+                    0 => return Ok(n),
+                    size => {
+                        self.send(Message::Binary(buf[..size].to_vec())).await?;
+                        n += size as u64;
+                    }
+                }
+            }
+        }
+
+        /// Close the connection with `code` and no reason.
+        /// see [`Connection::close_code`] for `code` of an application's own type.
+        #[inline]
+        pub async fn close_code(&mut self, code: impl Into<CloseCode>) -> Result<(), Error> {
+            self.send(Message::Close(Some(code.into().into()))).await
+        }
+
+        /// Write already-encoded frames to the connection as they are,
+        /// e.g. to replay captured traffic exactly.
+        /// 
+        /// **ADVANCED / TESTING API** : This bypasses the framing of mews :
+        /// 
+        /// * `bytes` must be complete, valid frames ( masked or not ), otherwise
+        ///   this fails with `InvalidInput` sending nothing.
+        /// * A close frame in `bytes` closes the connection the same as
+        ///   [`send`](WriteHalf::send) ( e.g. rewritten by `Config::on_close_send` ),
+        ///   so it must be the last frame.
+        /// * `Config::max_frame_size` and other limits are not applied.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::WriteHalf;
+        /// #
+        /// async fn replay(mut w: WriteHalf, captured: &[Vec<u8>]) -> std::io::Result<()> {
+        ///     for frames in captured {
+        ///         w.send_raw(frames).await?;
+        ///     }
+        ///     Ok(())
+        /// }
+        /// ```
+        pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<(), Error> {
+            let mut state = self.state.lock().await;
+            state.send_raw(bytes, &self.__closed__, &self.config).await
+        }
+
+        /// Send a close frame without waiting for the peer's one, then drop
+        /// this half, e.g. for an abusive client not worth the closing handshake.
+        /// 
+        /// The frame is queued after the buffered messages, and this returns as
+        /// soon as they're written to the connection, or after `CLOSE_NOW_TIMEOUT`
+        /// ( 100 ms ) even if not, e.g. when the client has stopped reading and
+        /// the socket is full. An error in sending it is ignored. The TCP connection
+        /// is closed when the `ReadHalf` is also dropped.
+        /// 
+        /// **note** : The peer may observe `CloseCode::Abnormal` ( 1006 ) if
+        /// it doesn't read the close frame before the connection is closed.
+        pub async fn close_now(self, frame: CloseFrame) {
+            let Self { state, __closed__, config, .. } = &self;
+            let _ = crate::websocket::with_timeout(crate::connection::CLOSE_NOW_TIMEOUT, async {
+                let mut state = state.lock().await;
+                state.check_open(__closed__).await?;
+                state.queue(Message::Close(Some(frame)), config)?;
+                state.drain(__closed__).await
+            }).await;
+        }
+    }
+
+    /// A text message being sent piece by piece, created by [`WriteHalf::start_text`].
+    /// 
+    /// Each `write` is sent as a non-final fragment ( flushed by its `FlushPolicy` ),
+    /// and `finish` sends and flushes the final one. Since every piece is a `&str`,
+    /// fragments never split a multi-byte character.
+    /// 
+    /// **note** : Dropping this without `finish` leaves the message unfinished.
+    /// It's finished by an empty final fragment just before the next data message,
+    /// so the peer receives the text written so far as the whole message.
+    #[must_use = "the message is unfinished until `.finish()` is called"]
+    pub struct TextWriter<'w, C: UnderlyingConnection = crate::runtime::TcpStream> {
+        half:    &'w mut WriteHalf<C>,
+        flush:   FlushPolicy,
+        /// number of fragments sent so far
+        written: usize,
+    }
+    impl<C: UnderlyingConnection> TextWriter<'_, C> {
+        /// Send `text` as the next fragment of the message.
+        pub async fn write(&mut self, text: &str) -> Result<(), Error> {
+            if text.is_empty() {
+                return Ok(())
+            }
+            self.send(text, false).await?;
+            self.written += 1;
+            Ok(())
+        }
+
+        /// Send the final fragment to complete the message.
+        pub async fn finish(mut self) -> Result<(), Error> {
+            self.send("", true).await
+        }
+
+        async fn send(&mut self, text: &str, is_final: bool) -> Result<(), Error> {
+            /* messages by `poll_send` must not be sent in the middle of this message */
+            std::future::poll_fn(|cx| self.half.poll_send_ready(cx)).await?;
+
+            let WriteHalf { __closed__, state, config, .. } = &*self.half;
+            let (is_first, flush) = (self.written == 0, self.flush.flushes(self.written + 1, is_final));
+            let opcode = if is_first {OpCode::Text} else {OpCode::Continue};
+            let mut state = state.lock().await;
+            state.check_open(__closed__).await?;
+            state.queue_fragment(opcode, text.as_bytes().to_vec(), is_first, is_final, config)?;
+            state.drain_if(flush, __closed__, config).await
+        }
+    }
 
-            flush(conn, n_buffered).await
+    /// When to flush the fragments of a message sent piece by piece,
+    /// e.g. by [`WriteHalf::start_text_with`].
+    /// 
+    /// Regardless of this, the buffer is flushed when it gets over
+    /// `Config::write_buffer_size`, and the last fragment is always flushed.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum FlushPolicy {
+        /// flush each fragment as soon as it's sent, for low latency
+        Each,
+        /// flush all the fragments together with the last one, for throughput
+        AtEnd,
+        /// flush every `n` fragments
+        Every(usize),
+    }
+    impl FlushPolicy {
+        /// whether to flush after the `n`-th ( 1-based ) fragment
+        pub(crate) fn flushes(self, n: usize, is_final: bool) -> bool {
+            is_final || match self {
+                Self::Each     => true,
+                Self::AtEnd    => false,
+                Self::Every(m) => n.is_multiple_of(m.max(1)),
+            }
         }
     }
 
     impl<C: UnderlyingConnection> Connection<C> {
         /// ## Panics
-        /// 
+        ///
         /// This panics if the original `Connection` is already closed.
-        pub fn split(self) -> (ReadHalf<C::ReadHalf>, WriteHalf<C::WriteHalf>) {
+        pub fn split(self) -> (ReadHalf<C>, WriteHalf<C>) {
             if !(*self.__closed__.try_read().expect(ALREADY_CLOSED_MESSAGE) == false) {
                 panic!("{ALREADY_CLOSED_MESSAGE}")
             }
 
-            #[cfg(feature="__splitref__")]
-            let conn = unsafe {&mut *self.conn.get()};
-            #[cfg(feature="__clone__")]
-            let conn = self.conn;
-
-            let (r, w) = conn.split();
-            let __closed__ = Arc::new(RwLock::new(false));
             (
-                ReadHalf  {
-                    __closed__: __closed__.clone(),
-                    conn: r,
-                    config: self.config.clone()
+                ReadHalf {
+                    __closed__: self.__closed__.clone(),
+                    state:      Some({
+                        self.lent.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+                        Arc::into_inner(self.reader).expect("the read state is in use by `Closer`").into_inner()
+                    }),
+                    receiving:  None,
+                    writer:     self.writer.clone(),
+                    config:     self.config.clone(),
+                    deferred:   None,
+                    received_unmasked: self.received_unmasked,
+                    activity:   self.activity,
+                    #[cfg(feature="__splitref__")]
+                    __conn__:   self.__conn__.clone(),
                 },
                 WriteHalf {
-                    __closed__,
-                    conn: w,
-                    config: self.config,
-                    n_buffered: self.n_buffered
+                    __closed__: self.__closed__,
+                    state:      self.writer,
+                    config:     self.config,
+                    sending:    None,
+                    #[cfg(feature="__splitref__")]
+                    __conn__:   self.__conn__,
                 },
             )
         }
     }
 }
+
+pub mod boxed {
+    use crate::runtime::{AsyncRead, AsyncWrite};
+    use std::{pin::Pin, task::{Context, Poll}, io};
+
+    /// # Type-erased underlying connection
+    /// 
+    /// Wraps any connection implementing `AsyncRead` and `AsyncWrite` of the
+    /// selected runtime (e.g. `TcpStream` or a TLS stream), to keep connections
+    /// of different types together, e.g. as `Vec<WebSocket<BoxedConnection>>`.
+    /// 
+    /// **note** : Every read and write goes through dynamic dispatch, costing
+    /// an indirect call per IO operation, which is negligible compared to
+    /// the IO itself. `Connection::peer_addr` and the socket options of `Config`
+    /// ( e.g. `tcp_linger` ) aren't available for it, since the `TcpStream` is hidden.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{WebSocket, BoxedConnection};
+    /// #
+    /// async fn manage_all(sessions: Vec<(WebSocket<BoxedConnection>, BoxedConnection)>) {
+    ///     for (ws, conn) in sessions {
+    ///         tokio::spawn(ws.manage(conn));
+    ///     }
+    /// }
+    /// 
+    /// fn boxed(tcp: tokio::net::TcpStream) -> BoxedConnection {
+    ///     BoxedConnection::new(tcp)
+    /// }
+    /// ```
+    #[cfg(feature="__splitref__")]
+    pub struct BoxedConnection(Pin<Box<dyn Duplex>>);
+    /* `__clone__` runtimes split connections by `Clone`: share the connection between the halves */
+    #[cfg(feature="__clone__")]
+    #[derive(Clone)]
+    pub struct BoxedConnection(std::sync::Arc<std::sync::Mutex<Pin<Box<dyn Duplex>>>>);
+
+    trait Duplex: AsyncRead + AsyncWrite + Send + Sync {}
+    impl<T: AsyncRead + AsyncWrite + Send + Sync> Duplex for T {}
+
+    impl BoxedConnection {
+        #[cfg(feature="__splitref__")]
+        pub fn new(conn: impl AsyncRead + AsyncWrite + Send + Sync + 'static) -> Self {
+            Self(Box::pin(conn))
+        }
+        #[cfg(feature="__clone__")]
+        pub fn new(conn: impl AsyncRead + AsyncWrite + Send + Sync + 'static) -> Self {
+            Self(std::sync::Arc::new(std::sync::Mutex::new(Box::pin(conn))))
+        }
+
+        #[cfg(feature="__splitref__")]
+        fn inner(&mut self) -> Pin<&mut dyn Duplex> {
+            self.0.as_mut()
+        }
+        #[cfg(feature="__clone__")]
+        fn inner(&mut self) -> impl std::ops::DerefMut<Target = Pin<Box<dyn Duplex>>> + '_ {
+            /* the lock is held only within a `poll_*`, never across an `.await` */
+            self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+        }
+    }
+
+    impl std::fmt::Debug for BoxedConnection {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("BoxedConnection").finish_non_exhaustive()
+        }
+    }
+
+    #[cfg(any(feature="rt_tokio", feature="rt_nio"))]
+    const _: () = {
+        impl AsyncRead for BoxedConnection {
+            fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<io::Result<()>> {
+                self.get_mut().inner().poll_read(cx, buf)
+            }
+        }
+        impl AsyncWrite for BoxedConnection {
+            fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                self.get_mut().inner().poll_write(cx, buf)
+            }
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.get_mut().inner().poll_flush(cx)
+            }
+            fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.get_mut().inner().poll_shutdown(cx)
+            }
+        }
+
+        impl<'split> super::split::Splitable<'split> for BoxedConnection {
+            type ReadHalf  = tokio::io::ReadHalf <&'split mut BoxedConnection>;
+            type WriteHalf = tokio::io::WriteHalf<&'split mut BoxedConnection>;
+            fn split(&'split mut self) -> (Self::ReadHalf, Self::WriteHalf) {
+                tokio::io::split(self)
+            }
+        }
+    };
+
+    #[cfg(any(feature="rt_async-std", feature="rt_smol", feature="rt_glommio"))]
+    const _: () = {
+        impl AsyncRead for BoxedConnection {
+            fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                self.get_mut().inner().as_mut().poll_read(cx, buf)
+            }
+        }
+        impl AsyncWrite for BoxedConnection {
+            fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                self.get_mut().inner().as_mut().poll_write(cx, buf)
+            }
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.get_mut().inner().as_mut().poll_flush(cx)
+            }
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.get_mut().inner().as_mut().poll_close(cx)
+            }
+        }
+    };
+}
+
+pub mod ping {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex, pin::Pin, future::Future};
+    use std::task::{Context, Poll, Waker};
+    use std::time::{Duration, Instant};
+
+    /// # Correlated pings
+    /// 
+    /// Assigns each outgoing ping a correlation id (big-endian `u64` as its payload)
+    /// and resolves a [`Pong`] future per ping with its round-trip time when
+    /// the matching pong is handled by [`handle_pong`](Pinger::handle_pong).
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{Connection, Message, ping::Pinger};
+    /// #
+    /// async fn handler(conn: Connection) {
+    ///     let pinger = Pinger::new();
+    /// 
+    ///     let pong = pinger.ping(&conn).await
+    ///         .expect("failed to send ping");
+    ///     tokio::spawn(async move {
+    ///         println!("RTT: {:?}", pong.await);
+    ///     });
+    /// 
+    ///     while let Ok(message) = conn.recv().await {
+    ///         match message {
+    ///             Some(Message::Pong(payload)) => {pinger.handle_pong(&payload);}
+    ///             Some(Message::Close(_)) => break,
+    ///             _ => ()
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    #[derive(Clone, Default)]
+    pub struct Pinger {
+        pending: Arc<Mutex<Pending>>,
+    }
+
+    /// What [`Pinger`] does on a new ping when the limit of outstanding pings is reached,
+    /// see [`Pinger::with_limit`].
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum PingOverflow {
+        /// fail to create the new ping
+        Reject,
+        /// forget the oldest outstanding ping to accept the new one,
+        /// whose `Pong` never resolves then
+        DropOldest,
+    }
+
+    #[derive(Default)]
+    struct Pending {
+        next_id: u64,
+        slots:   HashMap<u64, Slot>,
+        limit:   Option<(usize, PingOverflow)>,
+    }
+    struct Slot {
+        sent_at: Instant,
+        rtt:     Option<Duration>,
+        waker:   Option<Waker>,
+    }
+
+    impl Pinger {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// `Pinger` holding up to `limit` pings awaiting their pongs, applying
+        /// `on_overflow` to a new ping over it, e.g. when pinging faster than
+        /// pongs return.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::ping::{Pinger, PingOverflow};
+        /// #
+        /// let pinger = Pinger::with_limit(16, PingOverflow::DropOldest);
+        /// ```
+        pub fn with_limit(limit: usize, on_overflow: PingOverflow) -> Self {
+            let pinger = Self::new();
+            pinger.pending.lock().unwrap().limit = Some((limit, on_overflow));
+            pinger
+        }
+
+        /// Create a ping message with a new correlation id, and the
+        /// `Pong` future to be resolved by the corresponded pong.
+        /// 
+        /// The ping message is expected to be sent immediately after this.
+        /// 
+        /// ## Panics
+        /// 
+        /// This panics if the limit of `PingOverflow::Reject` is reached.
+        /// Use [`try_ping_message`](Pinger::try_ping_message) to handle it.
+        pub fn ping_message(&self) -> (Message, Pong) {
+            self.try_ping_message().expect("too many outstanding pings")
+        }
+
+        /// Same as [`ping_message`](Pinger::ping_message), but fails if the
+        /// limit of `PingOverflow::Reject` is reached.
+        pub fn try_ping_message(&self) -> Result<(Message, Pong), Error> {
+            let mut pending = self.pending.lock().unwrap();
+
+            if let Some((limit, on_overflow)) = pending.limit {
+                if pending.outstanding() >= limit {
+                    match on_overflow {
+                        PingOverflow::Reject => return Err(Error::other(
+                            format!("{limit} pings are already awaiting their pongs")
+                        )),
+                        PingOverflow::DropOldest => {
+                            let oldest = pending.slots.iter()
+                                .filter(|(_, slot)| slot.rtt.is_none())
+                                .min_by_key(|(_, slot)| slot.sent_at)
+                                .map(|(id, _)| *id);
+                            if let Some(id) = oldest {
+                                pending.slots.remove(&id);
+                            }
+                        }
+                    }
+                }
+            }
+
+            let id = pending.next_id;
+            pending.next_id = pending.next_id.wrapping_add(1);
+            pending.slots.insert(id, Slot { sent_at: Instant::now(), rtt: None, waker: None });
+
+            Ok((
+                Message::Ping(id.to_be_bytes().to_vec()),
+                Pong { id, pending: self.pending.clone() }
+            ))
+        }
+
+        /// Send a ping with a new correlation id via the connection.
+        /// 
+        /// This fails without sending if the limit of `PingOverflow::Reject` is reached.
+        pub async fn ping<C: UnderlyingConnection>(&self, conn: &Connection<C>) -> Result<Pong, Error> {
+            let (message, pong) = self.try_ping_message()?;
+            conn.send(message).await?;
+            Ok(pong)
+        }
+
+        /// Resolve the `Pong` corresponded to the pong payload.
+        /// 
+        /// returns `false` if the payload doesn't match any outstanding ping.
+        pub fn handle_pong(&self, payload: &[u8]) -> bool {
+            let Ok(id) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+                return false
+            };
+
+            let mut pending = self.pending.lock().unwrap();
+            match pending.slots.get_mut(&id) {
+                Some(slot) if slot.rtt.is_none() => {
+                    slot.rtt = Some(slot.sent_at.elapsed());
+                    if let Some(waker) = slot.waker.take() {
+                        waker.wake()
+                    }
+                    true
+                }
+                _ => false
+            }
+        }
+
+        /// Number of pings awaiting their pongs.
+        pub fn outstanding(&self) -> usize {
+            self.pending.lock().unwrap().outstanding()
+        }
+    }
+
+    impl Pending {
+        fn outstanding(&self) -> usize {
+            self.slots.values()
+                .filter(|slot| slot.rtt.is_none())
+                .count()
+        }
+    }
+
+    /// Future resolving to the round-trip time of a ping created by [`Pinger`].
+    /// 
+    /// **note** : This never resolves if the peer doesn't respond. Consider
+    /// awaiting it with a timeout.
+    #[must_use = "`Pong` does nothing unless awaited"]
+    pub struct Pong {
+        id:      u64,
+        pending: Arc<Mutex<Pending>>,
+    }
+    impl Pong {
+        /// Correlation id of the ping.
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+    }
+    impl Future for Pong {
+        type Output = Duration;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut pending = self.pending.lock().unwrap();
+            let Some(slot) = pending.slots.get_mut(&self.id) else {
+                return Poll::Pending
+            };
+            match slot.rtt {
+                Some(rtt) => Poll::Ready(rtt),
+                None => {
+                    slot.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+    impl Drop for Pong {
+        fn drop(&mut self) {
+            if let Ok(mut pending) = self.pending.lock() {
+                pending.slots.remove(&self.id);
+            }
+        }
+    }
+}
+
+pub mod manager {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex as SyncMutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// # Registry of connections
+    /// 
+    /// Assigns each registered connection a unique id and keeps its write side
+    /// to send messages to it from anywhere. A connection is removed when
+    /// the [`Registered`] returned by [`register`](ConnectionManager::register)
+    /// is dropped, typically at the end of the handler.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{Connection, Message, manager::ConnectionManager};
+    /// #
+    /// async fn chat(conn: Connection, manager: ConnectionManager) {
+    ///     let registered = manager.register(&conn);
+    /// 
+    ///     while let Ok(Some(message)) = conn.recv().await {
+    ///         if let Message::Text(text) = message {
+    ///             manager.broadcast(format!("{}: {text}", registered.id())).await;
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub struct ConnectionManager<C: UnderlyingConnection = crate::runtime::TcpStream> {
+        inner: Arc<Inner<C>>,
+    }
+
+    struct Inner<C: UnderlyingConnection> {
+        next_id:       AtomicU64,
+        connections:   SyncMutex<HashMap<u64, Entry<C>>>,
+        flush_timeout: std::time::Duration,
+    }
+
+    /// default timeout of flushing to each connection in [`ConnectionManager::broadcast`]
+    /// and [`ConnectionManager::close_all`]
+    pub const FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    struct Entry<C: UnderlyingConnection> {
+        __closed__: Arc<RwLock<bool>>,
+        writer:     Arc<Mutex<WriteState<WriteHalfOf<C>>>>,
+        config:     Config,
+        activity:   Arc<crate::frame::Activity>,
+        handshake:  Arc<Handshake>,
+
+        /// keeps the underlying connection alive for `writer` after the handler
+        /// and `Closer` dropped it (dropped last)
+        #[cfg(feature="__splitref__")]
+        __conn__: Arc<std::cell::UnsafeCell<C>>,
+    }
+
+    /// Aggregates over the registered connections, see [`ConnectionManager::snapshot`].
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct ManagerStats {
+        /// connections not closing
+        pub open:    usize,
+        /// connections in the closing handshake : a close frame is sent or received
+        pub closing: usize,
+        /// connections finished the closing handshake or disconnected,
+        /// until their handlers finish
+        pub closed:  usize,
+        /// bytes buffered to be written over the connections
+        pub buffered_bytes: usize,
+        /// how long the oldest connection has been connected, `None` without connections
+        pub oldest_age: Option<std::time::Duration>,
+    }
+
+    /// Registration of a connection in `ConnectionManager`, removing it on drop.
+    #[must_use = "the connection is removed from the manager when `Registered` is dropped"]
+    pub struct Registered<C: UnderlyingConnection = crate::runtime::TcpStream> {
+        id:    u64,
+        inner: Arc<Inner<C>>,
+    }
+
+    const _: (/* trait impls */) = {
+        // SAFETY: the write sides are only accessed through `Mutex`es,
+        // in the same way as `Connection`, and each entry keeps its
+        // underlying connection alive by `__conn__`
+        unsafe impl<C: UnderlyingConnection> Send for ConnectionManager<C> {}
+        unsafe impl<C: UnderlyingConnection> Sync for ConnectionManager<C> {}
+        unsafe impl<C: UnderlyingConnection> Send for Registered<C> {}
+        unsafe impl<C: UnderlyingConnection> Sync for Registered<C> {}
+
+        impl<C: UnderlyingConnection> Clone for ConnectionManager<C> {
+            fn clone(&self) -> Self {
+                Self { inner: self.inner.clone() }
+            }
+        }
+        impl<C: UnderlyingConnection> Default for ConnectionManager<C> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+        impl<C: UnderlyingConnection> Clone for Entry<C> {
+            fn clone(&self) -> Self {
+                Self {
+                    __closed__: self.__closed__.clone(),
+                    writer:     self.writer.clone(),
+                    config:     self.config.clone(),
+                    activity:   self.activity.clone(),
+                    handshake:  self.handshake.clone(),
+                    #[cfg(feature="__splitref__")]
+                    __conn__:   self.__conn__.clone(),
+                }
+            }
+        }
+
+        impl<C: UnderlyingConnection> Drop for Registered<C> {
+            fn drop(&mut self) {
+                self.inner.connections.lock().unwrap().remove(&self.id);
+            }
+        }
+
+        impl<C: UnderlyingConnection> std::fmt::Debug for ConnectionManager<C> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct("ConnectionManager")
+                    .field("connections", &self.len())
+                    .finish()
+            }
+        }
+    };
+
+    impl<C: UnderlyingConnection> Registered<C> {
+        /// the id assigned to the connection
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    impl<C: UnderlyingConnection> ConnectionManager<C> {
+        pub fn new() -> Self {
+            Self::with_flush_timeout(FLUSH_TIMEOUT)
+        }
+
+        /// Same as [`new`](ConnectionManager::new), but with the timeout of flushing
+        /// to each connection in `broadcast` and `close_all` instead of [`FLUSH_TIMEOUT`].
+        pub fn with_flush_timeout(flush_timeout: std::time::Duration) -> Self {
+            Self { inner: Arc::new(Inner {
+                next_id:     AtomicU64::new(0),
+                connections: SyncMutex::new(HashMap::new()),
+                flush_timeout,
+            }) }
+        }
+
+        /// Register `conn` with a new unique id.
+        pub fn register(&self, conn: &Connection<C>) -> Registered<C> {
+            let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+            self.inner.connections.lock().unwrap().insert(id, Entry {
+                __closed__: conn.__closed__.clone(),
+                writer:     conn.writer.clone(),
+                config:     conn.config.clone(),
+                activity:   conn.activity.clone(),
+                handshake:  conn.handshake.clone(),
+                #[cfg(feature="__splitref__")]
+                __conn__:   conn.__conn__.clone(),
+            });
+            Registered { id, inner: self.inner.clone() }
+        }
+
+        /// number of the registered connections
+        pub fn len(&self) -> usize {
+            self.inner.connections.lock().unwrap().len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// Aggregate the states of the registered connections, e.g. for a health endpoint.
+        /// 
+        /// This doesn't wait for anything : a connection being written at the time
+        /// is not counted in `buffered_bytes`, so it's approximate under load.
+        /// 
+        /// *example.rs*
+        /// ```
+        /// # use mews::manager::ConnectionManager;
+        /// #
+        /// fn healthz(manager: &ConnectionManager) -> String {
+        ///     let stats = manager.snapshot();
+        ///     format!("open={} closing={} buffered={}", stats.open, stats.closing, stats.buffered_bytes)
+        /// }
+        /// ```
+        pub fn snapshot(&self) -> ManagerStats {
+            use std::sync::atomic::Ordering::SeqCst;
+
+            let now = std::time::Instant::now();
+            let mut stats = ManagerStats { open: 0, closing: 0, closed: 0, buffered_bytes: 0, oldest_age: None };
+            for entry in self.inner.connections.lock().unwrap().values() {
+                let handshake = &entry.handshake;
+                if handshake.is_done() {
+                    stats.closed += 1
+                } else if handshake.sent.load(SeqCst) || handshake.received.load(SeqCst) {
+                    stats.closing += 1
+                } else {
+                    stats.open += 1
+                }
+
+                /* `try_lock` returns `Result` or `Option` depending on the runtime */
+                stats.buffered_bytes += entry.writer.try_lock().into_iter()
+                    .map(|writer| writer.buffer.len() - writer.written)
+                    .sum::<usize>();
+
+                let age = now.saturating_duration_since(entry.activity.connected_at());
+                stats.oldest_age = stats.oldest_age.max(Some(age));
+            }
+            stats
+        }
+
+        /// Send `message` to the connection of `id`.
+        /// 
+        /// returns an error of `ErrorKind::NotFound` if no such connection is registered.
+        pub async fn send_to(&self, id: u64, message: impl Into<Message>) -> Result<(), Error> {
+            let entry = self.inner.connections.lock().unwrap().get(&id).cloned()
+                .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, format!("No connection of id {id}")))?;
+            let mut writer = entry.writer.lock().await;
+            writer.send(message.into(), &entry.__closed__, &entry.config).await
+        }
+
+        /// Send `message` to all the registered connections.
+        /// 
+        /// The connections are sent to concurrently, each given up after the flush
+        /// timeout ( [`FLUSH_TIMEOUT`] by default ), so a slow or non-reading peer
+        /// doesn't delay the others. The message stays buffered for a peer given up,
+        /// to be written by its next sending.
+        /// 
+        /// returns the number of connections it has been sent to, skipping
+        /// the ones failed to send ( e.g. already closed ) or given up.
+        pub async fn broadcast(&self, message: impl Into<Message>) -> usize {
+            let message = message.into();
+            let sent = join_all(self.entries().into_iter().map(|entry| {
+                let message = message.clone();
+                crate::websocket::with_timeout(self.inner.flush_timeout, async move {
+                    let mut writer = entry.writer.lock().await;
+                    writer.send(message, &entry.__closed__, &entry.config).await
+                })
+            })).await;
+            sent.into_iter().filter(|sent| matches!(sent, Some(Ok(())))).count()
+        }
+
+        /// Close all the registered connections with `frame`.
+        /// 
+        /// The connections are closed concurrently, each given up after the
+        /// flush timeout the same as [`broadcast`](ConnectionManager::broadcast).
+        pub async fn close_all(&self, frame: CloseFrame) {
+            let closed = join_all(self.entries().into_iter().map(|entry| {
+                let frame = frame.clone();
+                crate::websocket::with_timeout(self.inner.flush_timeout, async move {
+                    let mut writer = entry.writer.lock().await;
+                    writer.close_with(frame, &entry.__closed__, &entry.config).await
+                })
+            })).await;
+            for closed in closed {
+                match closed {
+                    Some(Ok(())) => (),
+                    Some(Err(e)) => eprintln!("failed to send a close message: {e}"),
+                    None => eprintln!("failed to send a close message: timed out"),
+                }
+            }
+        }
+
+        fn entries(&self) -> Vec<Entry<C>> {
+            self.inner.connections.lock().unwrap().values().cloned().collect()
+        }
+    }
+
+    /// Run `tasks` concurrently, returning their outputs in order.
+    async fn join_all<F: std::future::Future>(tasks: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+        let mut tasks = tasks.into_iter().map(|task| (Box::pin(task), None)).collect::<Vec<_>>();
+        std::future::poll_fn(|cx| {
+            let mut pending = false;
+            for (task, output) in &mut tasks {
+                if output.is_none() {
+                    match task.as_mut().poll(cx) {
+                        std::task::Poll::Ready(done) => *output = Some(done),
+                        std::task::Poll::Pending => pending = true,
+                    }
+                }
+            }
+            if pending {
+                return std::task::Poll::Pending
+            }
+            std::task::Poll::Ready(tasks.iter_mut().map(|(_, output)| output.take().unwrap()).collect())
+        }).await
+    }
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_peer_addr_ipv6() {
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (conn, _closer) = Connection::new(server, Config::default());
+        let peer_addr = conn.peer_addr().unwrap();
+        assert_eq!(peer_addr, client.local_addr().unwrap());
+        assert!(peer_addr.to_string().starts_with("[::1]:"));
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_recv_timeout_in_frame() {
+    use {crate::__test__::masked, tokio::io::AsyncWriteExt, std::time::Duration};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+
+        let frame = masked(0x81, b"Hello");
+        client.write_all(&frame[..6/* header */]).await.unwrap();
+        let error = conn.recv_timeout(Duration::from_millis(10)).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+
+        client.write_all(&frame[6..]).await.unwrap();
+        let message = conn.recv_timeout(Duration::from_secs(1)).await.unwrap();
+        assert!(matches!(message, Some(Message::Text(text)) if text == "Hello"));
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_shutdown() {
+    use {crate::__test__::masked, tokio::io::{AsyncReadExt, AsyncWriteExt}};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+
+        client.write_all(&[masked(0x81, b"in flight"), masked(0x88, &[0x03, 0xe9/* 1001 */])].concat()).await.unwrap();
+        let reply = conn.shutdown().await.unwrap();
+        assert_eq!(reply, Some(CloseFrame { code: CloseCode::Away, reason: None }));
+        assert!(conn.is_closed().await);
+
+        let mut bytes = [0; 4];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0x88, 0x02, 0x03, 0xe8/* 1000 */]);
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_coalesce_pongs() {
+    use {crate::__test__::masked, tokio::io::{AsyncReadExt, AsyncWriteExt}, std::time::Duration};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config { coalesce_pongs: true, read_ahead: true, ..Default::default() }).await;
+
+        client.write_all(&[masked(0x89, b"1"), masked(0x89, b"2"), masked(0x89, b"3"), masked(0x81, b"done")].concat()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        for _ in 0..3 {
+            assert!(conn.recv().await.unwrap().is_none());
+        }
+        assert!(matches!(conn.recv().await.unwrap(), Some(Message::Text(text)) if text == "done"));
+
+        let mut bytes = [0; 3];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0x8a, 0x01, b'3']);
+        conn.send("end").await.unwrap();
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes[0], 0x81, "only one pong is sent");
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_recv_any() {
+    use {crate::__test__::masked, tokio::io::AsyncWriteExt};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+
+        client.write_all(&[masked(0x89, b"ping"), masked(0x81, b"text"), masked(0x88, b"")].concat()).await.unwrap();
+        assert!(matches!(conn.recv_any().await.unwrap(), Event::Ping(payload) if payload == b"ping"));
+        assert!(matches!(conn.recv_any().await.unwrap(), Event::Message(Message::Text(text)) if text == "text"));
+        assert!(matches!(conn.recv_any().await.unwrap(), Event::Close(None)));
+        assert_eq!(conn.writer.lock().await.n_buffered(), 0);
+        assert!(!conn.is_closed().await, "nothing is sent automatically");
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_recv_batch() {
+    use {crate::__test__::masked, tokio::io::{AsyncReadExt, AsyncWriteExt}, std::time::Duration};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config { read_ahead: true, ..Default::default() }).await;
+        let (mut r, _w) = conn.split();
+
+        client.write_all(&[masked(0x81, b"a"), masked(0x89, b"p"), masked(0x81, b"b"), masked(0x81, b"c")].concat()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let texts = |batch: Vec<Message>| batch.into_iter().map(|m| match m {Message::Text(t) => t, _ => panic!("{m:?}")}).collect::<Vec<_>>();
+        assert_eq!(texts(r.recv_batch(2, None).await.unwrap()), ["a", "b"]);
+        assert_eq!(texts(r.recv_batch(2, None).await.unwrap()), ["c"]);
+        let deadline = std::time::Instant::now() + Duration::from_millis(10);
+        assert!(r.recv_batch(2, Some(deadline)).await.unwrap().is_empty());
+
+        let mut bytes = [0; 3];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0x8a, 0x01, b'p']);
+    })
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_min_ping_interval() {
+    use crate::__test__::block_on;
+
+    let mut writer = WriteState::new(Vec::new());
+    let (__closed__, config) = (RwLock::new(false), Config { min_ping_interval: Some(std::time::Duration::from_secs(3600)), ..Default::default() });
+    for _ in 0..3 {
+        block_on(writer.send(Message::Ping(b"flood".to_vec()), &__closed__, &config)).unwrap();
+    }
+    block_on(writer.send(Message::Pong(b"pong".to_vec()), &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, [&[0x89, 0x05][..], b"flood", &[0x8a, 0x04], b"pong"].concat());
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_text_writer() {
+    use tokio::io::AsyncReadExt;
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+        let (_r, mut w) = conn.split();
+
+        let mut text = w.start_text();
+        text.write("Hel").await.unwrap();
+        text.write("").await.unwrap();
+        text.write("lo").await.unwrap();
+        text.finish().await.unwrap();
+        assert_eq!(w.pending_write_bytes().await, 0);
+        w.write("buffered").await.unwrap();
+        assert_eq!(w.pending_write_bytes().await, 2 + 8);
+
+        let mut bytes = [0; 11];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x01\x03Hel\x00\x02lo\x80\x00");
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_max_send_bytes_per_second() {
+    use tokio::io::AsyncReadExt;
+
+    tokio::runtime::Builder::new_current_thread().enable_io().enable_time().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config { max_send_bytes_per_second: Some(100), ..Default::default() }).await;
+
+        let read = tokio::spawn(async move {
+            let mut bytes = [0; 4 + 146];
+            client.read_exact(&mut bytes).await.map(|_| bytes)
+        });
+        let start = std::time::Instant::now();
+        conn.send(Message::Binary(vec![42; 146])).await.unwrap();
+        /* 100 bytes at first, then 50 bytes paced */
+        assert!(start.elapsed() >= std::time::Duration::from_millis(400));
+        assert_eq!(read.await.unwrap().unwrap()[..4], [0x82, 126, 0, 146]);
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_min_read_rate() {
+    use {crate::ReadRate, tokio::io::AsyncWriteExt, std::time::Duration};
+
+    tokio::runtime::Builder::new_current_thread().enable_io().enable_time().build().unwrap().block_on(async {
+        let min_read_rate = ReadRate { bytes_per_second: 100, window: Duration::from_millis(100) };
+        let (mut client, conn, _closer) = crate::__test__::connected(Config { min_read_rate: Some(min_read_rate), ..Default::default() }).await;
+
+        tokio::spawn(async move {
+            for b in crate::__test__::masked(0x81, b"slowly") {
+                if client.write_all(&[b]).await.is_err() {break}
+                tokio::time::sleep(Duration::from_millis(30)).await;
+            }
+        });
+        let error = conn.recv().await.unwrap_err();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Policy));
+
+        /* stopping in the middle of the header, closed without waiting for more bytes */
+        let (mut client, conn, _closer) = crate::__test__::connected(Config { min_read_rate: Some(min_read_rate), ..Default::default() }).await;
+        client.write_all(&crate::__test__::masked(0x81, b"stopped")[..3]).await.unwrap();
+        let error = tokio::time::timeout(Duration::from_secs(1), conn.recv()).await
+            .expect("closed while the client sends nothing")
+            .unwrap_err();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Policy));
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_close_now() {
+    use tokio::io::AsyncReadExt;
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+        let (_r, w) = conn.split();
+
+        w.close_now(CloseCode::Policy.into()).await;
+        let mut bytes = [0; 4];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0x88, 0x02, 0x03, 0xf0]);
+
+        /* a client not reading with the socket full */
+        let (_client, conn, _closer) = crate::__test__::connected(Config { write_buffer_size: usize::MAX, ..Default::default() }).await;
+        let (_r, mut w) = conn.split();
+        w.write(Message::Binary(vec![0x42; 64 << 20])).await.unwrap();
+
+        let started = std::time::Instant::now();
+        w.close_now(CloseCode::Policy.into()).await;
+        assert!(started.elapsed() < std::time::Duration::from_secs(1), "returned without draining the buffer");
+    })
+}
+
+#[cfg(all(test, unix, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_socket_buffer_sizes() {
+    use std::os::fd::{AsRawFd, BorrowedFd};
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (_client, server) = crate::__test__::tcp_pair().await;
+        let socket = socket2::SockRef::from(&server);
+        let (default_recv, default_send) = (socket.recv_buffer_size().unwrap(), socket.send_buffer_size().unwrap());
+
+        /* quarter, as Linux doubles the requested sizes */
+        let config = Config { socket_recv_buffer: Some(default_recv / 4), socket_send_buffer: Some(default_send / 4), ..Default::default() };
+        let fd = server.as_raw_fd();
+        let (_conn, _closer) = Connection::new(server, config);
+        // SAFETY: the fd is owned by `_conn` alive until the end
+        let fd = unsafe {BorrowedFd::borrow_raw(fd)};
+        let socket = socket2::SockRef::from(&fd);
+        assert!(socket.recv_buffer_size().unwrap() < default_recv);
+        assert!(socket.send_buffer_size().unwrap() < default_send);
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_boxed_connection() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (mut client, server) = crate::__test__::tcp_pair().await;
+        let (conn, _closer) = Connection::new(boxed::BoxedConnection::new(server), Config::default());
+        assert!(conn.peer_addr().is_none());
+
+        client.write_all(&crate::__test__::masked(0x81, b"ping")).await.unwrap();
+        assert!(matches!(conn.recv().await.unwrap(), Some(Message::Text(text)) if text == "ping"));
+
+        conn.send("pong").await.unwrap();
+        let mut bytes = [0; 6];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x81\x04pong");
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_pipe() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+        let (mut r, mut w) = conn.split();
+
+        assert_eq!(w.pipe_from(&mut &b"from reader"[..]).await.unwrap(), 11);
+        let mut bytes = [0; 2 + 11];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x82\x0bfrom reader");
+
+        use crate::__test__::masked;
+        client.write_all(&[masked(0x82, b"to "), masked(0x82, b"writer"), masked(0x88, &[0x03, 0xe8])].concat()).await.unwrap();
+        let mut piped = Vec::new();
+        assert_eq!(r.pipe_to(&mut piped).await.unwrap(), 9);
+        assert_eq!(piped, b"to writer");
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_protocol_error() {
+    use tokio::io::AsyncReadExt;
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+
+        let error = conn.protocol_error("bad").await;
+        let frame = CloseFrame::from_error(&error).unwrap();
+        assert_eq!((&frame.code, frame.reason.as_deref()), (&CloseCode::Protocol, Some("bad")));
+
+        let mut bytes = [0; 7];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x88\x05\x03\xeabad");
+        assert!(AlreadyClosed::from_error(&conn.protocol_error("again").await).is_some());
+    })
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_closed() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = masked(0x88, &[0x03, 0xe8]);
+    let handshake = Arc::new(Handshake::default());
+    let mut reader = ReadState { conn: &bytes[..], messages: MessageReader::new(), pending_error: None, received_close: false, pending_pong: None, held: None, handshake: handshake.clone() };
+    let mut writer = WriteState::new(Vec::new());
+    writer.handshake = handshake.clone();
+    let writer = Mutex::new(writer);
+    let (__closed__, config) = (RwLock::new(false), Config::default());
+
+    block_on(reader.recv_with_info(&writer, &__closed__, &config)).unwrap().unwrap();
+    assert!(!handshake.is_done());
+    block_on(async {writer.lock().await.send(Message::Close(None), &__closed__, &config).await}).unwrap();
+    assert!(handshake.is_done());
+    block_on(handshake.done());
+
+    let handshake = Arc::new(Handshake::default());
+    drop(ReadState { conn: &b""[..], messages: MessageReader::new(), pending_error: None, received_close: false, pending_pong: None, held: None, handshake: handshake.clone() });
+    assert!(handshake.is_done());
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_send_tls_close_code() {
+    use crate::__test__::block_on;
+
+    let mut writer = WriteState::new(Vec::new());
+    let (__closed__, config) = (RwLock::new(false), Config::default());
+
+    for code in [CloseCode::Tls, CloseCode::Status, CloseCode::Abnormal] {
+        let error = block_on(writer.send(Message::Close(Some(code.clone().into())), &__closed__, &config)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let error = block_on(writer.close_with(code.into(), &__closed__, &config)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+    assert!(writer.conn.is_empty() && !writer.closing, "nothing is sent");
+
+    block_on(writer.send(Message::Close(Some(CloseCode::Normal.into())), &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, [0x88, 0x02, 0x03, 0xe8]);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_send_raw() {
+    use crate::__test__::block_on;
+
+    let mut writer = WriteState::new(Vec::new());
+    let (__closed__, config) = (RwLock::new(false), Config::default());
+
+    let frames = [&b"\x01\x03abc"[..], b"\x89\x00", b"\x80\x01d"].concat();
+    block_on(writer.send_raw(&frames, &__closed__, &config)).unwrap();
+    block_on(writer.send(Message::Text("e".into()), &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, [&frames[..], b"\x81\x01e"].concat());
+
+    /* an unfinished fragmented message is recorded the same as by `send` */
+    block_on(writer.send_raw(b"\x02\x01f", &__closed__, &config)).unwrap();
+    block_on(writer.send(Message::Text("g".into()), &__closed__, &config)).unwrap();
+    assert!(writer.conn.ends_with(b"\x02\x01f\x80\x00\x81\x01g"));
+
+    for invalid in [
+        &b"\x81\x05abc"[..]/* incomplete */,
+        b"\x83\x00"/* reserved opcode */,
+        b"\x88\x01\x03"/* invalid close payload */,
+        b"\x88\x00\x81\x01h"/* following a close frame */,
+    ] {
+        let before = writer.conn.len();
+        let error = block_on(writer.send_raw(invalid, &__closed__, &config)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(writer.conn.len(), before, "nothing is sent");
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_send_raw_close() {
+    use crate::{__test__::block_on, CloseHook};
+
+    let __closed__ = RwLock::new(false);
+    let config = Config {
+        on_close_send: Some(CloseHook::new(|frame| CloseFrame { code: frame.code, reason: None })),
+        ..Default::default()
+    };
+    let mut writer = WriteState::new(Vec::new());
+
+    /* a masked close frame of 1011 "oops" */
+    let close = [&b"\x88\x86\x01\x02\x03\x04"[..], &[0x03 ^ 0x01, 0xf3 ^ 0x02, b'o' ^ 0x03, b'o' ^ 0x04, b'p' ^ 0x01, b's' ^ 0x02]].concat();
+    block_on(writer.send_raw(&[&b"\x81\x01a"[..], &close].concat(), &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, b"\x81\x01a\x88\x02\x03\xf3", "closed the same as by `send`");
+    assert!(writer.closing);
+
+    let error = block_on(writer.send_raw(b"\x81\x01b", &__closed__, &config)).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_on_close_send() {
+    use crate::{__test__::block_on, CloseHook};
+
+    let __closed__ = RwLock::new(false);
+    let config = Config {
+        on_close_send: Some(CloseHook::new(|frame| CloseFrame { code: frame.code, reason: None })),
+        ..Default::default()
+    };
+    let mut writer = WriteState::new(Vec::new());
+    let frame = CloseFrame { code: CloseCode::Error, reason: Some("database password is wrong".into()) };
+    block_on(writer.close_with(frame, &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, [0x88, 0x02, 0x03, 0xf3]);
+
+    /* the rewritten frame is validated */
+    for rewritten in [CloseFrame::from(CloseCode::Tls), CloseFrame { code: CloseCode::Normal, reason: Some("x".repeat(124).into()) }] {
+        let config = Config { on_close_send: Some(CloseHook::new(move |_| rewritten.clone())), ..Default::default() };
+        let mut writer = WriteState::new(Vec::new());
+        let error = block_on(writer.send(Message::Close(Some(CloseCode::Normal.into())), &RwLock::new(false), &config)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(writer.conn.is_empty());
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_send_after_close() {
+    use crate::__test__::block_on;
+
+    let mut writer = WriteState::new(Vec::new());
+    let (__closed__, config) = (RwLock::new(false), Config::default());
+    block_on(writer.send(Message::Close(None), &__closed__, &config)).unwrap();
+
+    for error in [
+        block_on(writer.send(Message::Text("late".into()), &__closed__, &config)).unwrap_err(),
+        block_on(writer.write(Message::Text("late".into()), &__closed__, &config)).unwrap_err(),
+        block_on(writer.flush(&__closed__)).unwrap_err(),
+    ] {
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(AlreadyClosed::from_error(&error).is_some());
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_discard_after_close() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = [masked(0x81, b"ignored"), masked(0x88, &[0x03, 0xe8])].concat();
+    let mut reader = ReadState { conn: &bytes[..], messages: MessageReader::new(), pending_error: None, received_close: false, pending_pong: None, held: None, handshake: Default::default() };
+    let writer = Mutex::new(WriteState::new(Vec::new()));
+    let (__closed__, config) = (RwLock::new(true/* we've sent a close frame */), Config::default());
+
+    let (message, _) = block_on(reader.recv_with_info(&writer, &__closed__, &config)).unwrap().unwrap();
+    assert!(matches!(message, Message::Close(Some(CloseFrame { code: CloseCode::Normal, .. }))));
+    assert!(block_on(reader.recv_with_info(&writer, &__closed__, &config)).is_err());
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_flush_policy() {
+    let flushed = |policy: FlushPolicy| (1..=5).filter(|&n| policy.flushes(n, n == 5)).collect::<Vec<_>>();
+    assert_eq!(flushed(FlushPolicy::Each),     [1, 2, 3, 4, 5]);
+    assert_eq!(flushed(FlushPolicy::AtEnd),    [5]);
+    assert_eq!(flushed(FlushPolicy::Every(2)), [2, 4, 5]);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_send_stream() {
+    use crate::{__test__::block_on, frame::Decoded};
+
+    struct Parts(std::vec::IntoIter<Vec<u8>>);
+    impl futures_core::Stream for Parts {
+        type Item = Vec<u8>;
+        fn poll_next(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<Option<Vec<u8>>> {
+            std::task::Poll::Ready(self.0.next())
+        }
+    }
+
+    let mut writer = WriteState::new(Vec::new());
+    let (__closed__, config) = (RwLock::new(false), Config { accept_unmasked_frames: true, ..Default::default() });
+    let parts = Parts(vec![b"[1,".to_vec(), b"2,".to_vec(), b"3]".to_vec()].into_iter());
+    block_on(writer.send_stream(OpCode::Text, parts, FlushPolicy::AtEnd, &__closed__, &config)).unwrap();
+
+    let mut frames = vec![];
+    let mut rem = &writer.conn[..];
+    while let Ok(Decoded::Frame(frame, size)) = Frame::decode(rem, &config) {
+        frames.push((frame.is_final, frame.opcode, frame.payload));
+        rem = &rem[size..];
+    }
+    assert_eq!(frames, [
+        (false, OpCode::Text,     b"[1,".to_vec()),
+        (false, OpCode::Continue, b"2,".to_vec()),
+        (true,  OpCode::Continue, b"3]".to_vec()),
+    ]);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_eof_without_close() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = masked(0x81, b"last words");
+    let mut reader = ReadState { conn: &bytes[..], messages: MessageReader::new(), pending_error: None, received_close: false, pending_pong: None, held: None, handshake: Default::default() };
+    let writer = Mutex::new(WriteState::new(Vec::new()));
+    let (__closed__, config) = (RwLock::new(false), Config::default());
+
+    let (message, _) = block_on(reader.recv_with_info(&writer, &__closed__, &config)).unwrap().unwrap();
+    assert!(matches!(message, Message::Text(text) if text == "last words"));
+
+    let error = block_on(reader.recv_with_info(&writer, &__closed__, &config)).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Abnormal));
+    assert!(block_on(writer.lock()).conn.is_empty(), "1006 must not be sent");
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_manager_snapshot() {
+    use {crate::__test__::masked, tokio::io::AsyncWriteExt};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let manager = manager::ConnectionManager::new();
+        assert_eq!(manager.snapshot(), manager::ManagerStats { open: 0, closing: 0, closed: 0, buffered_bytes: 0, oldest_age: None });
+
+        let mut clients = Vec::new();
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let (client, conn, _) = crate::__test__::connected(Config::default()).await;
+            clients.push(client);
+            conns.push(conn);
+        }
+        let registered = conns.iter().map(|conn| manager.register(conn)).collect::<Vec<_>>();
+
+        conns[0].writer.lock().await.write(Message::Text("buffered".into()), &conns[0].__closed__, &conns[0].config).await.unwrap();
+        clients[1].write_all(&masked(0x88, &[0x03, 0xe8])).await.unwrap();
+        conns[1].recv().await.unwrap();
+
+        let stats = manager.snapshot();
+        assert_eq!((stats.open, stats.closing, stats.closed), (1, 1, 0));
+        assert_eq!(stats.buffered_bytes, 2 + "buffered".len());
+        assert!(stats.oldest_age.is_some());
+
+        drop(registered);
+        assert_eq!(manager.snapshot().open, 0);
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_simultaneous_close() {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (client, server) = crate::__test__::tcp_pair().await;
+
+        /* both ends are mews connections sending unmasked frames to each other */
+        let config = Config { accept_unmasked_frames: true, ..Default::default() };
+        let (a, _a_closer) = Connection::new(server, config.clone());
+        let (b, _b_closer) = Connection::new(client, config);
+
+        /* both close frames are on the wire before either side receives */
+        a.close_code(CloseCode::Away).await.unwrap();
+        b.close_code(CloseCode::Normal).await.unwrap();
+
+        let (a, b) = (Arc::new(a), Arc::new(b));
+        let a_shutdown = tokio::spawn({let a = a.clone(); async move {a.shutdown().await}});
+        let b_shutdown = tokio::spawn({let b = b.clone(); async move {b.shutdown().await}});
+        assert_eq!(a_shutdown.await.unwrap().unwrap().map(|f| f.code), Some(CloseCode::Normal));
+        assert_eq!(b_shutdown.await.unwrap().unwrap().map(|f| f.code), Some(CloseCode::Away));
+
+        a.closed().await;
+        b.closed().await;
+        assert!(a.send("late").await.is_err() && b.send("late").await.is_err());
+    })
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_ping_limit() {
+    use ping::{Pinger, PingOverflow};
+
+    let payload = |message: Message| match message {Message::Ping(payload) => payload, _ => unreachable!()};
+
+    let pinger = Pinger::with_limit(2, PingOverflow::Reject);
+    let (first, _first_pong) = pinger.try_ping_message().unwrap();
+    let _second = pinger.try_ping_message().unwrap();
+    assert!(pinger.try_ping_message().is_err());
+    assert!(pinger.handle_pong(&payload(first)));
+    assert!(pinger.try_ping_message().is_ok(), "the answered ping is not outstanding");
+
+    let pinger = Pinger::with_limit(2, PingOverflow::DropOldest);
+    let (first, _first_pong) = pinger.try_ping_message().unwrap();
+    let (second, _second_pong) = pinger.try_ping_message().unwrap();
+    let _third = pinger.try_ping_message().unwrap();
+    assert_eq!(pinger.outstanding(), 2);
+    assert!(!pinger.handle_pong(&payload(first)), "the oldest ping is dropped");
+    assert!(pinger.handle_pong(&payload(second)));
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_drain_inbound() {
+    use {crate::__test__::masked, tokio::io::{AsyncReadExt, AsyncWriteExt}, std::time::Duration};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config::default()).await;
+        let (mut r, _w) = conn.split();
+
+        assert_eq!(r.drain_inbound().await.unwrap(), 0, "nothing is waited for");
+
+        client.write_all(&[masked(0x81, b"a"), masked(0x89, b"p"), masked(0x82, b"b")].concat()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(r.drain_inbound().await.unwrap(), 2);
+        let mut bytes = [0; 3];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0x8a, 0x01, b'p']);
+
+        client.write_all(&[masked(0x81, b"c"), masked(0x88, &[0x03, 0xe8]), masked(0x81, b"d")].concat()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(r.drain_inbound().await.unwrap(), 1);
+        assert!(matches!(r.recv().await.unwrap(), Some(Message::Close(Some(CloseFrame { code: CloseCode::Normal, .. })))));
+    })
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_send_non_reserved_opcode() {
+    use crate::__test__::block_on;
+
+    let mut writer = WriteState::new(Vec::new());
+    let (__closed__, config) = (RwLock::new(false), Config::default());
+
+    for opcode in [0x0, 0x1, 0x2, 0x8, 0x9, 0xa, 0x13] {
+        let error = block_on(writer.send(Message::Reserved { opcode, payload: vec![] }, &__closed__, &config)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+    assert!(writer.conn.is_empty() && !writer.closing, "nothing is sent");
+
+    block_on(writer.send(Message::Reserved { opcode: 0x3, payload: vec![] }, &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, [0x83, 0x00]);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_send_frame_validated() {
+    use crate::{__test__::block_on, CloseHook};
+
+    let __closed__ = RwLock::new(false);
+    let config = Config {
+        max_frame_size: Some(4),
+        on_close_send:  Some(CloseHook::new(|frame| CloseFrame { code: frame.code, reason: None })),
+        ..Default::default()
+    };
+    let mut writer = WriteState::new(Vec::new());
+
+    let frame = Frame { is_final: true, opcode: OpCode::Binary, payload: b"123456".to_vec() };
+    block_on(writer.send_frame(frame, &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, b"\x02\x041234\x80\x0256");
+
+    writer.conn.clear();
+    let frame = Frame { is_final: true, opcode: OpCode::Close, payload: b"\x03\xe8secret".to_vec() };
+    block_on(writer.send_frame(frame, &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, [0x88, 0x02, 0x03, 0xe8], "rewritten by `on_close_send`");
+    assert!(writer.closing);
+
+    let frame = Frame { is_final: true, opcode: OpCode::Text, payload: b"after".to_vec() };
+    let error = block_on(writer.send_frame(frame, &__closed__, &config)).unwrap_err();
+    assert!(AlreadyClosed::from_error(&error).is_some());
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_poll_send_wakeup() {
+    use std::time::{Duration, Instant};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (_client, conn, _closer) = crate::__test__::connected(Config { max_send_bytes_per_second: Some(100), ..Default::default() }).await;
+        let (_r, mut w) = conn.split();
+
+        /* only the pacing's sleep wakes the task after the first 100 bytes */
+        let (started, mut message) = (Instant::now(), Some(Message::Binary(vec![0; 142])));
+        let sending = std::future::poll_fn(|cx| match message.take() {
+            Some(message) => w.poll_send(cx, message),
+            None => w.poll_send_ready(cx),
+        });
+        tokio::time::timeout(Duration::from_secs(5), sending).await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2), "woken after {:?}", started.elapsed());
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_poll_recv_wakeup() {
+    use {crate::__test__::masked, tokio::io::AsyncWriteExt, std::time::{Duration, Instant}};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config { max_send_bytes_per_second: Some(100), ..Default::default() }).await;
+        let (mut r, mut w) = conn.split();
+
+        /* the write side is held for about 0.5 sec by the pacing */
+        let sending = tokio::spawn(async move {w.send(Message::Binary(vec![0; 142])).await});
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        /* a reserved opcode makes `poll_recv` wait for the write side to send a close frame */
+        client.write_all(&masked(0x83, b"")).await.unwrap();
+        let started = Instant::now();
+        let error = tokio::time::timeout(Duration::from_secs(5), std::future::poll_fn(|cx| r.poll_recv(cx))).await.unwrap().unwrap_err();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Protocol));
+        assert!(started.elapsed() < Duration::from_secs(2), "woken after {:?}", started.elapsed());
+        sending.await.unwrap().unwrap();
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_forward_to_validated() {
+    use {crate::__test__::masked, tokio::io::{AsyncReadExt, AsyncWriteExt}};
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (mut client_a, a, _a_closer) = crate::__test__::connected(Config::default()).await;
+        let (mut client_b, b, _b_closer) = crate::__test__::connected(Config { max_frame_size: Some(2), ..Default::default() }).await;
+
+        client_a.write_all(&[masked(0x82, b"abcd"), masked(0x88, &[0x03, 0xe8])].concat()).await.unwrap();
+        a.forward_to(&b).await.unwrap();
+
+        let mut bytes = [0; 12];
+        client_b.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x02\x02ab\x80\x02cd\x88\x02\x03\xe8", "refragmented by `b`'s `max_frame_size`");
+        assert!(b.is_closed().await, "closed by the forwarded close frame");
+        assert!(AlreadyClosed::from_error(&b.send("after").await.unwrap_err()).is_some());
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_manager_outlives_connection() {
+    use tokio::io::AsyncReadExt;
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let manager = manager::ConnectionManager::new();
+        let (mut client, conn, closer) = crate::__test__::connected(Config::default()).await;
+        let registered = manager.register(&conn);
+
+        /* the handler and the closer are done, but the registration isn't dropped yet */
+        drop((conn, closer));
+        assert_eq!(manager.broadcast("still alive").await, 1);
+
+        let mut bytes = [0; 2 + 11];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x81\x0bstill alive");
+        drop(registered);
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_manager_stalled_peer() {
+    use {tokio::io::AsyncReadExt, std::time::{Duration, Instant}};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let manager = manager::ConnectionManager::with_flush_timeout(Duration::from_millis(200));
+
+        /* not reading, with the socket full by the buffered message */
+        let (_stalled, stalled_conn, _) = crate::__test__::connected(Config { write_buffer_size: usize::MAX, ..Default::default() }).await;
+        stalled_conn.write(Message::Binary(vec![0x42; 64 << 20])).await.unwrap();
+        let (mut client, conn, _) = crate::__test__::connected(Config::default()).await;
+        let _registered = (manager.register(&stalled_conn), manager.register(&conn));
+
+        let started = Instant::now();
+        assert_eq!(manager.broadcast("hello").await, 1, "given up for the stalled peer");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let mut bytes = [0; 2 + 5];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x81\x05hello");
+
+        let started = Instant::now();
+        manager.close_all(CloseCode::Away.into()).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let mut bytes = [0; 4];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x88\x02\x03\xe9");
+    })
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_finish_fragmented() {
+    use crate::__test__::block_on;
+
+    let mut writer = WriteState::new(Vec::new());
+    let (__closed__, config) = (RwLock::new(false), Config::default());
+
+    /* left unfinished, e.g. by a dropped `TextWriter` */
+    writer.queue_fragment(OpCode::Text, b"ab".to_vec(), true, false, &config).unwrap();
+    block_on(writer.send(Message::Ping(vec![]), &__closed__, &config)).unwrap();
+    block_on(writer.send(Message::Text("c".into()), &__closed__, &config)).unwrap();
+    assert_eq!(writer.conn, b"\x01\x02ab\x89\x00\x80\x00\x81\x01c", "finished just before the next data message");
+
+    let error = writer.queue_fragment(OpCode::Text, b"d".to_vec(), false, true, &config).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput, "the finished message isn't continued");
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_coalesced_pong_cancelled() {
+    use {crate::__test__::masked, tokio::io::{AsyncReadExt, AsyncWriteExt}, std::time::Duration};
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        let (mut client, conn, _closer) = crate::__test__::connected(Config { coalesce_pongs: true, read_ahead: true, ..Default::default() }).await;
+
+        client.write_all(&[masked(0x89, b"ping"), masked(0x81, b"data")].concat()).await.unwrap();
+        assert!(conn.recv().await.unwrap().is_none(), "the `Pong` is held back by the buffered frame");
+
+        /* the write side is busy, and `recv` is cancelled in sending the `Pong` */
+        let writer = conn.writer.lock().await;
+        assert!(tokio::time::timeout(Duration::from_millis(50), conn.recv()).await.is_err());
+        drop(writer);
+
+        let message = conn.recv().await.unwrap();
+        assert!(matches!(message, Some(Message::Text(text)) if text == "data"), "not lost by the cancellation");
+        let mut pong = [0; 6];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, *b"\x8a\x04ping");
+    })
+}
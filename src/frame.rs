@@ -1,9 +1,9 @@
 use std::io::{Error, ErrorKind};
-use crate::runtime::{Read, Write};
-use crate::Config;
+use crate::runtime::Read;
+use crate::{Config, CloseFrame, CloseCode, UnknownOpcode, message::{human_size, check_message_size}};
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub(crate) enum OpCode {
     /* data op codes */
     Continue /* 0x0 */,
@@ -14,7 +14,7 @@ pub(crate) enum OpCode {
     Ping     /* 0x9 */,
     Pong     /* 0xa */,
     /* reserved op codes */
-    // Reserved /* 0x[3-7,b-f] */,
+    Reserved(u8) /* 0x[3-7,b-f] */,
 }
 impl OpCode {
     #[inline]
@@ -22,8 +22,7 @@ impl OpCode {
         Ok(match byte {
             0x0 => Self::Continue, 0x1 => Self::Text, 0x2 => Self::Binary,
             0x8 => Self::Close,    0x9 => Self::Ping, 0xa => Self::Pong,
-            0x3..=0x7 | 0xb..=0xf => return Err(Error::new(
-                ErrorKind::Unsupported, "Ohkami doesn't handle reserved opcodes")),
+            0x3..=0x7 | 0xb..=0xf => Self::Reserved(byte),
             _ => return Err(Error::new(
                 ErrorKind::InvalidData, "OpCode out of range")),
         })
@@ -34,6 +33,7 @@ impl OpCode {
         match self {
             Self::Continue => 0x0, Self::Text => 0x1, Self::Binary => 0x2,
             Self::Close    => 0x8, Self::Ping => 0x9, Self::Pong   => 0xa,
+            Self::Reserved(byte) => byte & 0x0F,
         }
     }
 }
@@ -45,62 +45,156 @@ pub(crate) struct Frame {
     pub(crate) payload:  Vec<u8>,
 }
 
+#[cfg(feature="__runtime__")]
+pub(crate) enum Decoded {
+    /// a frame and the number of bytes it occupied
+    Frame(Frame, usize),
+    /// the buffer has to have at least this number of bytes to make progress
+    Incomplete(usize),
+}
+
 #[cfg(feature="__runtime__")]
 impl Frame {
-    pub(crate) async fn read_from(
-        stream: &mut (impl Read + Unpin),
-        config: &Config,
-    ) -> Result<Option<Self>, Error> {
-        let [first, second] = {
-            let mut head = [0; 2];
-            stream.read_exact(&mut head).await?;
-            head
+    /// Decode a frame from the head of `buf` without any IO.
+    #[cfg(any(test, feature="fuzzing"))]
+    #[inline]
+    pub(crate) fn decode(buf: &[u8], config: &Config) -> Result<Decoded, Error> {
+        Self::decode_following(buf, config, None)
+    }
+
+    /// Same as `decode`, but a continuation frame is taken as following
+    /// `fragmented`, the opcode and the assembled bytes of a fragmented message.
+    /// 
+    /// A data frame over `Config::max_frame_size` or making the message over
+    /// its size limit ( see `check_message_size` ) is rejected from its header,
+    /// without waiting for the payload.
+    pub(crate) fn decode_following(buf: &[u8], config: &Config, fragmented: Option<(OpCode, usize)>) -> Result<Decoded, Error> {
+        Self::decode_into(buf, config, fragmented, &mut Vec::new())
+    }
+
+    /// Split `buf` into complete, valid frames, each with the bytes it occupies,
+    /// e.g. pre-encoded bytes to be sent. `None` if `buf` doesn't consist of such frames.
+    pub(crate) fn split_frames(mut buf: &[u8]) -> Option<Vec<(Self, &[u8])>> {
+        let config = Config {
+            accept_unmasked_frames: true,
+            max_message_size:       None,
+            max_frame_size:         None,
+            ..Default::default()
+        };
+        let mut frames = Vec::new();
+        while !buf.is_empty() {
+            match Self::decode_following(buf, &config, None) {
+                Ok(Decoded::Frame(frame, size)) => {
+                    frames.push((frame, &buf[..size]));
+                    buf = &buf[size..];
+                }
+                _ => return None
+            }
+        }
+        Some(frames)
+    }
+
+    /// Same as `decode_following`, but the payload of a control frame is
+    /// decoded into `spare` when it has enough capacity, without allocation.
+    fn decode_into(buf: &[u8], config: &Config, fragmented: Option<(OpCode, usize)>, spare: &mut Vec<u8>) -> Result<Decoded, Error> {
+        let [first, second] = match buf {
+            [first, second, ..] => [*first, *second],
+            _ => return Ok(Decoded::Incomplete(2))
         };
 
         let is_final = first & 0x80 != 0;
         let opcode   = OpCode::from_byte(first & 0x0F)?;
+        if let OpCode::Reserved(byte) = opcode {
+            (config.on_unknown_opcode == UnknownOpcode::Deliver).then_some(())
+                .ok_or_else(|| CloseFrame::error(CloseCode::Protocol, format!("Reserved opcode {byte:#x}")))?;
+        }
+        if !is_final && matches!(opcode, OpCode::Close | OpCode::Ping | OpCode::Pong) {
+            return Err(CloseFrame::error(CloseCode::Protocol, format!("Fragmented control frame of {opcode:?}")))
+        }
 
-        let payload_len = {
-            let payload_len_byte = second & 0x7F;
-            let len_part_size = match payload_len_byte {127=>8, 126=>2, _=>0};
+        let payload_len_byte = second & 0x7F;
+        let len_part_size = match payload_len_byte {127=>8, 126=>2, _=>0};
+        let is_masked = second & 0x80 != 0;
+
+        let header_size = 2 + len_part_size + if is_masked {4} else {0};
+        if buf.len() < header_size {
+            return Ok(Decoded::Incomplete(header_size))
+        }
 
+        let payload_len = {
             let len = match len_part_size {
-                0 => payload_len_byte as usize,
+                0 => payload_len_byte as u64,
                 _ => {
                     let mut bytes = [0; 8];
-                    if let Err(e) = stream.read_exact(&mut bytes[(8 - len_part_size)..]).await {
-                        return match e.kind() {
-                            ErrorKind::UnexpectedEof => Ok(None),
-                            _                        => Err(e.into()),
-                        }
-                    }
-                    usize::from_be_bytes(bytes)
+                    bytes[(8 - len_part_size)..].copy_from_slice(&buf[2..(2 + len_part_size)]);
+                    u64::from_be_bytes(bytes)
                 }
-            }; if let Some(limit) = &config.max_frame_size {
+            };
+
+            if config.strict_length_encoding {
+                let is_minimal = match len_part_size {
+                    0 => true,
+                    2 => len > 125,
+                    _ => len > 65535,
+                };
+                is_minimal.then_some(())
+                    .ok_or_else(|| CloseFrame::error(
+                        CloseCode::Protocol,
+                        format!("frame length {len} has non-minimal encoding")
+                    ))?;
+            }
+
+            /* the 64-bit length may not fit in `usize` on 32-bit targets */
+            let len = usize::try_from(len).ok()
+                .filter(|len| len.checked_add(header_size).is_some())
+                .ok_or_else(|| CloseFrame::error(
+                    CloseCode::Size,
+                    format!("frame length {len} exceeds the address space")
+                ))?;
+            if let Some(limit) = &config.max_frame_size {
                 (&len <= limit).then_some(())
-                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Incoming frame is too large"))?;
+                    .ok_or_else(|| CloseFrame::error(
+                        CloseCode::Size,
+                        format!("frame {} exceeds limit {}", human_size(len), human_size(*limit))
+                    ))?;
+            }
+            match opcode {
+                OpCode::Continue => match fragmented {
+                    Some((opcode, assembled)) => check_message_size(opcode, assembled.saturating_add(len), config)?,
+                    None => check_message_size(opcode, len, config)?
+                },
+                OpCode::Text | OpCode::Binary | OpCode::Reserved(_) => check_message_size(opcode, len, config)?,
+                OpCode::Close | OpCode::Ping | OpCode::Pong => ()
             }
 
             len
         };
 
-        let mask = if second & 0x80 == 0 {
+        let mask = if !is_masked {
             (config.accept_unmasked_frames).then_some(None)
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Client frame is unmasked"))?
+        } else if config.accept_unmasked_frames && config.skip_unmasking {
+            None
         } else {
-            let mut mask_bytes = [0; 4];
-            if let Err(e) = stream.read_exact(&mut mask_bytes).await {
-                return match e.kind() {
-                    ErrorKind::UnexpectedEof => Ok(None),
-                    _                        => Err(e.into()),
-                }
-            }
-            Some(mask_bytes)
+            let mask_start = 2 + len_part_size;
+            Some([buf[mask_start], buf[mask_start + 1], buf[mask_start + 2], buf[mask_start + 3]])
         };
 
+        let frame_size = header_size + payload_len;
+        if buf.len() < frame_size {
+            return Ok(Decoded::Incomplete(frame_size))
+        }
+
         let payload = {
-            let mut payload = vec![0u8; payload_len];
-            stream.read_exact(&mut payload).await?;
+            let mut payload = match opcode {
+                OpCode::Ping | OpCode::Pong | OpCode::Close if spare.capacity() >= payload_len => {
+                    let mut payload = std::mem::take(spare);
+                    payload.clear();
+                    payload.extend_from_slice(&buf[header_size..frame_size]);
+                    payload
+                }
+                _ => buf[header_size..frame_size].to_vec()
+            };
 
             if let Some(masking_bytes) = mask {
                 let mut i = 0;
@@ -117,33 +211,413 @@ impl Frame {
             payload
         };
 
-        Ok(Some(Self { is_final, opcode, payload }))
+        Ok(Decoded::Frame(Self { is_final, opcode, payload }, frame_size))
     }
 
-    pub(crate) async fn write_unmasked(self,
-        stream:  &mut (impl Write + Unpin),
-        _config: &Config,
-    ) -> Result<usize, Error> {
-        fn into_bytes(frame: Frame) -> Vec<u8> {
-            let Frame { is_final, opcode, payload } = frame;
+    /// Frames carrying `payload` as a part of a data message of `opcode`,
+    /// each within `Config::max_frame_size`.
+    /// 
+    /// `is_first` / `is_final` : whether the part is the first / last of the message
+    pub(crate) fn fragments(opcode: OpCode, payload: Vec<u8>, is_first: bool, is_final: bool, config: &Config) -> Vec<Frame> {
+        let opcode = if is_first {opcode} else {OpCode::Continue};
+        match config.max_frame_size {
+            Some(limit) if payload.len() > limit => {
+                let n_frames = payload.len().div_ceil(limit.max(1));
+                payload.chunks(limit.max(1)).enumerate().map(|(i, chunk)| Frame {
+                    is_final: is_final && i == n_frames - 1,
+                    opcode:   if i == 0 {opcode} else {OpCode::Continue},
+                    payload:  chunk.to_vec(),
+                }).collect()
+            }
+            _ => vec![Frame { is_final, opcode, payload }]
+        }
+    }
 
-            let (payload_len_byte, payload_len_bytes) = match payload.len() {
-                ..=125      => (payload.len() as u8, None),
-                126..=65535 => (126, Some((|len: u16| len.to_be_bytes().to_vec())(payload.len() as u16))),
-                _           => (127, Some((|len: u64| len.to_be_bytes().to_vec())(payload.len() as u64))),
+    /// Encode the frame unmasked to the end of `buf`, returning the number of bytes.
+    pub(crate) fn encode_unmasked(self, buf: &mut Vec<u8>) -> usize {
+        let Frame { is_final, opcode, payload } = self;
+        Self::encode_unmasked_parts(is_final, opcode, &payload, buf)
+    }
+
+    /// Same as `encode_unmasked`, but from the parts of a frame, borrowing the payload.
+    pub(crate) fn encode_unmasked_parts(is_final: bool, opcode: OpCode, payload: &[u8], buf: &mut Vec<u8>) -> usize {
+
+        let (payload_len_byte, payload_len_bytes) = match payload.len() {
+            ..=125      => (payload.len() as u8, None),
+            126..=65535 => (126, Some((|len: u16| len.to_be_bytes().to_vec())(payload.len() as u16))),
+            _           => (127, Some((|len: u64| len.to_be_bytes().to_vec())(payload.len() as u64))),
+        };
+
+        let first  = ((is_final as u8) << 7) + opcode.into_byte();
+        let second = (0/* MASK: off */ << 7) + payload_len_byte;
+
+        let start = buf.len();
+        buf.extend_from_slice(&[first, second]);
+        if let Some(payload_len_bytes) = payload_len_bytes {
+            buf.extend_from_slice(&payload_len_bytes)
+        }
+        buf.extend_from_slice(payload);
+
+        buf.len() - start
+    }
+
+    /// Encode the frame masked by `key` as a client does, e.g. to script a client.
+    pub(crate) fn encode_masked(self, key: [u8; 4], buf: &mut Vec<u8>) -> usize {
+        let start = buf.len();
+        let size = self.encode_unmasked(buf);
+        let header_size = match buf[start + 1] {
+            126 => 4,
+            127 => 10,
+            _   => 2,
+        };
+        buf[start + 1] |= 0x80/* MASK: on */;
+        buf.splice(start + header_size..start + header_size, key);
+        for (i, byte) in buf[start + header_size + 4..].iter_mut().enumerate() {
+            *byte ^= key[i % 4]
+        }
+        size + 4
+    }
+}
+
+/// A frame decoded by [`decode_frame`], with the payload unmasked.
+#[cfg(feature="fuzzing")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawFrame {
+    pub is_final: bool,
+    pub opcode:   u8,
+    pub payload:  Vec<u8>,
+}
+
+/// Decode a frame from the head of `input` without any IO, by the same
+/// decoder as connections, with `config`.
+/// 
+/// returns the frame and the number of bytes it occupied, or `None` if
+/// `input` doesn't contain a complete frame yet. An error carries a
+/// `CloseFrame` ( see [`CloseFrame::from_error`] ) when the connection
+/// would be closed with it.
+/// 
+/// This is an entry point for fuzzing the parser, e.g. by `cargo fuzz`
+/// with the target in `fuzz/`.
+#[cfg(feature="fuzzing")]
+pub fn decode_frame(input: &[u8], config: &Config) -> Result<Option<(RawFrame, usize)>, Error> {
+    Ok(match Frame::decode(input, config)? {
+        Decoded::Frame(Frame { is_final, opcode, payload }, size) => {
+            Some((RawFrame { is_final, opcode: opcode.into_byte(), payload }, size))
+        }
+        Decoded::Incomplete(_) => None,
+    })
+}
+
+/// Buffered reader of frames from the underlying connection.
+/// 
+/// Read bytes are kept in the buffer until they compose a complete frame,
+/// so a partially-received frame is resumed by the next read.
+#[cfg(feature="__runtime__")]
+pub(crate) struct FrameReader {
+    buf:    Vec<u8>,
+    filled: usize,
+    /// whether an unmasked frame has been accepted by `Config::accept_unmasked_frames`
+    received_unmasked: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    activity: std::sync::Arc<Activity>,
+    /// when the first byte of the partially-buffered frame arrived, for `Config::min_read_rate`
+    partial_since: Option<std::time::Instant>,
+    /// recycled buffer to decode the next control frame's payload into
+    spare: Vec<u8>,
+}
+
+/// Header and the leading payload bytes of a received frame, for `Config::log_invalid_frame`.
+/// 
+/// `bytes` may be an incomplete frame, and the fields not received yet are shown as `?`.
+#[cfg(any(feature="tracing", test))]
+struct FrameDump<'b> {
+    bytes:  &'b [u8],
+    prefix: usize,
+}
+#[cfg(any(feature="tracing", test))]
+impl std::fmt::Display for FrameDump<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(&first) = self.bytes.first() else {return f.write_str("(empty)")};
+        write!(f, "fin={} rsv={:#05b} opcode={:#x}", first >> 7, (first >> 4) & 0b111, first & 0x0F)?;
+
+        let Some(&second) = self.bytes.get(1) else {return f.write_str(" mask=? len=?")};
+        let is_masked = second & 0x80 != 0;
+        let len_part_size = match second & 0x7F {127=>8, 126=>2, _=>0};
+        write!(f, " mask={}", is_masked as u8)?;
+        let Some(len_part) = self.bytes.get(2..(2 + len_part_size)) else {return f.write_str(" len=?")};
+        let len = match len_part_size {
+            0 => (second & 0x7F) as u64,
+            _ => len_part.iter().fold(0, |len, b| len << 8 | *b as u64)
+        };
+        write!(f, " len={len}")?;
+
+        let header_size = 2 + len_part_size + if is_masked {4} else {0};
+        if self.prefix == 0 || self.bytes.len() < header_size {
+            return Ok(())
+        }
+        let mask = is_masked.then(|| &self.bytes[(header_size - 4)..header_size]);
+        let payload = &self.bytes[header_size..][..(self.bytes.len() - header_size).min(self.prefix)];
+        f.write_str(" payload=")?;
+        for (i, b) in payload.iter().enumerate() {
+            write!(f, "{:02x}", b ^ mask.map_or(0, |mask| mask[i % 4]))?;
+        }
+        if (payload.len() as u64) < len {
+            f.write_str("..")?;
+        }
+        Ok(())
+    }
+}
+
+/// When the connection started and last received a frame.
+#[cfg(feature="__runtime__")]
+pub(crate) struct Activity {
+    connected_at: std::time::Instant,
+    /// nanoseconds from `connected_at` to the last frame received
+    last_frame:   std::sync::atomic::AtomicU64,
+}
+#[cfg(feature="__runtime__")]
+impl Activity {
+    fn new() -> Self {
+        Self { connected_at: std::time::Instant::now(), last_frame: Default::default() }
+    }
+
+    pub(crate) fn connected_at(&self) -> std::time::Instant {
+        self.connected_at
+    }
+
+    pub(crate) fn last_activity(&self) -> std::time::Instant {
+        self.connected_at + std::time::Duration::from_nanos(self.last_frame.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    fn touch(&self) {
+        let elapsed = u64::try_from(self.connected_at.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.last_frame.store(elapsed, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature="__runtime__")]
+impl FrameReader {
+    /// minimum size of a read when `Config::read_ahead` is enabled
+    const READ_AHEAD_SIZE: usize = 8 * 1024;
+
+    pub(crate) fn new() -> Self {
+        Self { buf: Vec::new(), filled: 0, received_unmasked: Default::default(), activity: std::sync::Arc::new(Activity::new()), partial_since: None, spare: Vec::new() }
+    }
+
+    /// whether a whole frame is already in the buffer, taken without IO by the next read
+    pub(crate) fn has_buffered_frame(&self, config: &Config) -> bool {
+        !matches!(Frame::decode_following(&self.buf[..self.filled], config, None), Ok(Decoded::Incomplete(_)))
+    }
+
+    #[inline]
+    pub(crate) fn received_unmasked(&self) -> &std::sync::Arc<std::sync::atomic::AtomicBool> {
+        &self.received_unmasked
+    }
+
+    #[inline]
+    pub(crate) fn activity(&self) -> &std::sync::Arc<Activity> {
+        &self.activity
+    }
+
+    /// Keep `payload` of a control frame done with, to decode the next control
+    /// frame into it. Once recycled, answering `Ping`s takes no allocation.
+    pub(crate) fn recycle(&mut self, mut payload: Vec<u8>) {
+        /* payload limit of control frames */
+        const CONTROL_PAYLOAD_LIMIT: usize = 125;
+
+        if self.spare.capacity() < CONTROL_PAYLOAD_LIMIT {
+            payload.clear();
+            payload.reserve_exact(CONTROL_PAYLOAD_LIMIT);
+            self.spare = payload;
+        }
+    }
+
+    /// Read a frame from the stream, following `fragmented`, the opcode and
+    /// the assembled bytes of a fragmented message, if it's a continuation frame.
+    /// 
+    /// returns `Ok(None)` when the stream reached EOF on a frame boundary.
+    pub(crate) async fn read_frame(&mut self,
+        stream:    &mut (impl Read + Unpin),
+        config:    &Config,
+        fragmented: Option<(OpCode, usize)>,
+    ) -> Result<Option<Frame>, Error> {
+        loop {
+            let decoded = Frame::decode_into(&self.buf[..self.filled], config, fragmented, &mut self.spare);
+            #[cfg(feature="tracing")]
+            if let (Err(error), Some(prefix)) = (&decoded, config.log_invalid_frame) {
+                tracing::debug!(frame = %FrameDump { bytes: &self.buf[..self.filled], prefix }, %error, "received invalid frame");
+            }
+            let required = match decoded? {
+                Decoded::Frame(frame, size) => {
+                    if self.buf[1] & 0x80 == 0 {
+                        self.received_unmasked.store(true, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    self.activity.touch();
+                    self.consume(size);
+                    return Ok(Some(frame))
+                }
+                Decoded::Incomplete(required) => required
+            };
+
+            /* the time since `partial_since` when the frame becomes slower than `min_read_rate` */
+            let violated_at = match (&config.min_read_rate, self.partial_since) {
+                (Some(min), Some(since)) if min.bytes_per_second > 0 => {
+                    let violated_at = min.window.max(std::time::Duration::from_secs_f64(self.filled as f64 / min.bytes_per_second as f64));
+                    let elapsed = since.elapsed();
+                    if elapsed > violated_at {
+                        return Err(read_too_slow(min))
+                    }
+                    Some((min, violated_at.saturating_sub(elapsed)))
+                }
+                _ => None
             };
 
-            let first  = ((is_final as u8) << 7) + opcode.into_byte();
-            let second = (0/* MASK: off */ << 7) + payload_len_byte;
+            let want = if config.read_ahead {
+                (required - self.filled).max(Self::READ_AHEAD_SIZE)
+            } else {
+                required - self.filled
+            };
+            if self.buf.len() < self.filled + want {
+                self.buf.resize(self.filled + want, 0);
+            }
 
-            let mut header_bytes = vec![first, second];
-            if let Some(mut payload_len_bytes) = payload_len_bytes {
-                header_bytes.append(&mut payload_len_bytes)
+            let read = stream.read(&mut self.buf[self.filled..(self.filled + want)]);
+            let read = match violated_at {
+                /* close even while the client sends nothing more */
+                Some((min, left)) => crate::websocket::with_timeout(left, read).await
+                    .ok_or_else(|| read_too_slow(min))?,
+                None => read.await
+            };
+            match read? {
+                0 => return match self.filled {
+                    0 => Ok(None),
+                    _ => Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the middle of a frame"))
+                },
+                n => {
+                    if self.filled == 0 {
+                        self.partial_since = Some(std::time::Instant::now());
+                    }
+                    self.filled += n
+                }
             }
+        }
+    }
 
-            [header_bytes, payload].concat()
+    fn consume(&mut self, size: usize) {
+        self.buf.copy_within(size..self.filled, 0);
+        self.filled -= size;
+        self.partial_since = (self.filled > 0).then(std::time::Instant::now);
+        if self.filled == 0 && self.buf.len() > Self::READ_AHEAD_SIZE {
+            self.buf = Vec::new();
         }
+    }
+}
+
+#[cfg(feature="__runtime__")]
+fn read_too_slow(min: &crate::ReadRate) -> Error {
+    CloseFrame::error(CloseCode::Policy, format!("frame is received slower than {} bytes/sec", min.bytes_per_second))
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_recycle_control_payload() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = [masked(0x89, b"first"), masked(0x89, b"second"), masked(0x89, b"third")].concat();
+    let (mut stream, mut reader, config) = (&bytes[..], FrameReader::new(), Config::default());
+
+    let first = block_on(reader.read_frame(&mut stream, &config, None)).unwrap().unwrap();
+    reader.recycle(first.payload);
+    let spare = reader.spare.as_ptr();
+
+    let second = block_on(reader.read_frame(&mut stream, &config, None)).unwrap().unwrap();
+    assert_eq!((&*second.payload, second.payload.as_ptr()), (&b"second"[..], spare));
+    reader.recycle(second.payload);
+    let third = block_on(reader.read_frame(&mut stream, &config, None)).unwrap().unwrap();
+    assert_eq!((&*third.payload, third.payload.as_ptr()), (&b"third"[..], spare));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_skip_unmasking() {
+    let bytes = crate::__test__::masked(0x82, b"data");
+
+    let skipping = Config { accept_unmasked_frames: true, skip_unmasking: true, ..Default::default() };
+    let Decoded::Frame(frame, _) = Frame::decode(&bytes, &skipping).unwrap() else {panic!()};
+    assert_eq!(frame.payload, bytes[6..]);
+
+    let ignored = Config { skip_unmasking: true, ..Default::default() };
+    let Decoded::Frame(frame, _) = Frame::decode(&bytes, &ignored).unwrap() else {panic!()};
+    assert_eq!(frame.payload, b"data");
+}
 
-        stream.write(&into_bytes(self)).await
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_decode_partially_buffered() {
+    /* masked text frame of "Hello" from https://datatracker.ietf.org/doc/html/rfc6455#section-5.7 */
+    let bytes = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+    let config = Config::default();
+
+    let mut required = 0;
+    for filled in 0..bytes.len() {
+        match Frame::decode(&bytes[..filled], &config).unwrap() {
+            Decoded::Incomplete(r) => {assert!(r > filled); required = r}
+            Decoded::Frame(..) => panic!("decoded from incomplete {filled} bytes"),
+        }
     }
+    assert_eq!(required, bytes.len());
+
+    let Decoded::Frame(frame, size) = Frame::decode(&[&bytes[..], &bytes[..2]].concat(), &config).unwrap() else {
+        panic!("failed to decode complete frame")
+    };
+    assert_eq!(size, bytes.len());
+    assert!(frame.is_final);
+    assert_eq!(frame.opcode, OpCode::Text);
+    assert_eq!(frame.payload, b"Hello");
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_strict_length_encoding() {
+    /* "Hello" with its length in 16-bit encoding */
+    let bytes = [0x81, 0xfe, 0x00, 0x05, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+
+    let Decoded::Frame(frame, _) = Frame::decode(&bytes, &Config::default()).unwrap() else {
+        panic!("failed to decode complete frame")
+    };
+    assert_eq!(frame.payload, b"Hello");
+
+    let strict = Config { strict_length_encoding: true, ..Default::default() };
+    let error = Frame::decode(&bytes, &strict).err().expect("non-minimal length is accepted");
+    assert_eq!(CloseFrame::from_error(&error).unwrap().code, CloseCode::Protocol);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_decode_overflowing_length() {
+    /* masked binary frame declaring a payload of `u64::MAX` bytes */
+    let bytes = [&[0x82, 0xff][..], &u64::MAX.to_be_bytes(), &[0x37, 0xfa, 0x21, 0x3d]].concat();
+    let config = Config { max_frame_size: None, ..Default::default() };
+
+    let error = Frame::decode(&bytes, &config).err().expect("overflowing length is accepted");
+    assert_eq!(CloseFrame::from_error(&error).unwrap().code, CloseCode::Size);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_fragmented_control_frame() {
+    use crate::__test__::masked;
+
+    for first in [0x08/* Close */, 0x09/* Ping */, 0x0a/* Pong */] {
+        /* rejected from the header */
+        let error = Frame::decode(&masked(first, b"ping")[..2], &Config::default()).err().unwrap();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Protocol));
+        assert!(matches!(Frame::decode(&masked(0x80 | first, b"\x03\xe8"), &Config::default()), Ok(Decoded::Frame(..))));
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_frame_dump() {
+    /* masked text frame of "Hello" from https://datatracker.ietf.org/doc/html/rfc6455#section-5.7 */
+    let bytes = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+
+    assert_eq!(FrameDump { bytes: &bytes, prefix: 16 }.to_string(), "fin=1 rsv=0b000 opcode=0x1 mask=1 len=5 payload=48656c6c6f");
+    assert_eq!(FrameDump { bytes: &bytes, prefix: 2 }.to_string(), "fin=1 rsv=0b000 opcode=0x1 mask=1 len=5 payload=4865..");
+    assert_eq!(FrameDump { bytes: &bytes, prefix: 0 }.to_string(), "fin=1 rsv=0b000 opcode=0x1 mask=1 len=5");
+    assert_eq!(FrameDump { bytes: &bytes[..1], prefix: 16 }.to_string(), "fin=1 rsv=0b000 opcode=0x1 mask=? len=?");
+    assert_eq!(FrameDump { bytes: &[0x7a, 0x7e, 0x01], prefix: 16 }.to_string(), "fin=0 rsv=0b111 opcode=0xa mask=0 len=?");
+    assert_eq!(FrameDump { bytes: &[0x02, 0x7e, 0x01, 0x00, 0xff], prefix: 16 }.to_string(), "fin=0 rsv=0b000 opcode=0x2 mask=0 len=256 payload=ff..");
 }
@@ -41,7 +41,8 @@ mod runtime {
         tokio::net::TcpStream,
         tokio::io::AsyncReadExt as Read,
         tokio::io::AsyncWriteExt as Write,
-        tokio::sync::RwLock,
+        tokio::io::{AsyncRead, AsyncWrite},
+        tokio::sync::{RwLock, Mutex},
         tokio::time::sleep
     };
 
@@ -50,7 +51,8 @@ mod runtime {
         async_std::net::TcpStream,
         async_std::io::ReadExt as Read,
         async_std::io::WriteExt as Write,
-        async_std::sync::RwLock,
+        async_std::io::{Read as AsyncRead, Write as AsyncWrite},
+        async_std::sync::{RwLock, Mutex},
         async_std::task::sleep
     };
 
@@ -59,7 +61,8 @@ mod runtime {
         smol::net::TcpStream,
         smol::io::AsyncReadExt as Read,
         smol::io::AsyncWriteExt as Write,
-        smol::lock::RwLock,
+        smol::io::{AsyncRead, AsyncWrite},
+        smol::lock::{RwLock, Mutex},
     };
     #[cfg(feature="rt_smol")]
     pub async fn sleep(duration: std::time::Duration) {
@@ -71,7 +74,8 @@ mod runtime {
         nio::net::TcpStream,
         tokio::io::AsyncReadExt as Read,
         tokio::io::AsyncWriteExt as Write,
-        tokio::sync::RwLock,
+        tokio::io::{AsyncRead, AsyncWrite},
+        tokio::sync::{RwLock, Mutex},
         nio::time::sleep
     };
 
@@ -80,9 +84,80 @@ mod runtime {
         glommio::net::TcpStream,
         futures_util::AsyncReadExt as Read,
         futures_util::AsyncWriteExt as Write,
+        futures_util::{AsyncRead, AsyncWrite},
         glommio::sync::RwLock,
+        futures_util::lock::Mutex,
         glommio::timer::sleep
     };
+
+    /// perform `f` on the socket of `tcp`
+    #[cfg(any(feature="rt_tokio", feature="rt_smol"))]
+    pub fn with_socket<T>(tcp: &TcpStream, f: impl FnOnce(&socket2::SockRef<'_>) -> std::io::Result<T>) -> std::io::Result<T> {
+        f(&socket2::SockRef::from(tcp))
+    }
+    #[cfg(all(unix, any(feature="rt_async-std", feature="rt_glommio")))]
+    pub fn with_socket<T>(tcp: &TcpStream, f: impl FnOnce(&socket2::SockRef<'_>) -> std::io::Result<T>) -> std::io::Result<T> {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+        // SAFETY: the fd is owned by `tcp` and valid while it's borrowed
+        let fd = unsafe {BorrowedFd::borrow_raw(tcp.as_raw_fd())};
+        f(&socket2::SockRef::from(&fd))
+    }
+    #[cfg(all(windows, feature="rt_async-std"))]
+    pub fn with_socket<T>(tcp: &TcpStream, f: impl FnOnce(&socket2::SockRef<'_>) -> std::io::Result<T>) -> std::io::Result<T> {
+        use std::os::windows::io::{AsRawSocket, BorrowedSocket};
+        // SAFETY: the socket is owned by `tcp` and valid while it's borrowed
+        let socket = unsafe {BorrowedSocket::borrow_raw(tcp.as_raw_socket())};
+        f(&socket2::SockRef::from(&socket))
+    }
+    #[cfg(feature="rt_nio")]
+    pub fn with_socket<T>(_tcp: &TcpStream, _f: impl FnOnce(&socket2::SockRef<'_>) -> std::io::Result<T>) -> std::io::Result<T> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "`nio::net::TcpStream` doesn't expose its socket"))
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+mod __test__ {
+    /// drive a future on in-memory IO ( always ready ) to completion
+    pub(crate) fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        let mut future = std::pin::pin!(future);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let std::task::Poll::Ready(t) = future.as_mut().poll(&mut cx) {
+                return t
+            }
+        }
+    }
+
+    /// client frame masked with a fixed key
+    pub(crate) fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        let mut bytes = vec![first];
+        match payload.len() {
+            len @ ..=125 => bytes.push(0x80 | len as u8),
+            len @ 126..=65535 => {bytes.push(0x80 | 126); bytes.extend((len as u16).to_be_bytes())}
+            len => {bytes.push(0x80 | 127); bytes.extend((len as u64).to_be_bytes())}
+        }
+        bytes.extend(key);
+        bytes.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        bytes
+    }
+
+    /// a client socket and the server socket accepted from it, over loopback
+    #[cfg(all(feature="rt_tokio", feature="DEBUG"))]
+    pub(crate) async fn tcp_pair() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// a client socket and the `Connection` of `config` it's connected to
+    #[cfg(all(feature="rt_tokio", feature="DEBUG"))]
+    pub(crate) async fn connected(config: crate::Config) -> (tokio::net::TcpStream, crate::Connection, crate::connection::Closer<tokio::net::TcpStream>) {
+        let (client, server) = tcp_pair().await;
+        let (conn, closer) = crate::Connection::new(server, config);
+        (client, conn, closer)
+    }
 }
 
 pub mod message;
@@ -92,11 +167,18 @@ pub mod frame;
 pub mod websocket;
 #[cfg(feature="__runtime__")]
 pub mod connection;
+#[cfg(feature="__runtime__")]
+pub mod codec;
+#[cfg(feature="__runtime__")]
+pub mod testing;
 
-pub use message::{Message, CloseFrame, CloseCode};
+pub use message::{Message, CloseFrame, CloseCode, RecvInfo};
 #[cfg(feature="__runtime__")]
 pub use {
     websocket::*,
-    connection::Connection,
-    connection::split::{self, ReadHalf, WriteHalf},
+    connection::{Connection, ConnectionInfo, EffectiveConfig, AlreadyClosed},
+    connection::split::{self, ReadHalf, WriteHalf, FlushPolicy},
+    connection::ping::{self, Pinger},
+    connection::manager::{self, ConnectionManager, ManagerStats},
+    connection::boxed::BoxedConnection,
 };
@@ -1,29 +1,50 @@
 #[cfg(feature="__runtime__")]
 use {
     std::io::{Error, ErrorKind},
-    crate::runtime::{Read, Write},
-    crate::frame::{Frame, OpCode},
-    crate::Config,
+    crate::runtime::Read,
+    crate::frame::{Frame, FrameReader, OpCode},
+    crate::{Config, ReassemblyBudget},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Message {
     Text  (String),
     Binary(Vec<u8>),
     Ping  (Vec<u8>),
     Pong  (Vec<u8>),
+    /// `None` for a close frame without payload, meaning no status code
+    /// ( reported as `CloseCode::Status` (1005) by RFC 6455 ), which is
+    /// distinguished from `Some` of `CloseCode::Normal` (1000).
     Close (Option<CloseFrame>),
+    /// frame of a reserved opcode, only delivered with
+    /// `UnknownOpcode::Deliver` ( see `Config::on_unknown_opcode` ).
+    /// Sending one of a non-reserved `opcode` ( other than `0x3`-`0x7`,
+    /// `0xb`-`0xf` ) fails with an `ErrorKind::InvalidInput` error.
+    Reserved { opcode: u8, payload: Vec<u8> },
 }
 
-#[derive(Debug)]
+/// Metadata of a received message, returned by `recv_with_info`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecvInfo {
+    /// number of frames composing the message on the wire (`1` if not fragmented)
+    pub fragments: usize,
+}
+impl RecvInfo {
+    /// whether the message arrived fragmented into multiple frames
+    pub const fn is_fragmented(&self) -> bool {
+        self.fragments > 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct CloseFrame {
     pub code:   CloseCode,
     pub reason: Option<std::borrow::Cow<'static, str>>,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CloseCode {
     Normal, Away, Protocol, Unsupported, Status, Abnormal, Invalid,
-    Policy, Size, Extension, Error, Restart, Again, Tls, Reserved,
+    Policy, Size, Extension, Error, Restart, Again, BadGateway, Tls, Reserved,
     Iana(u16), Library(u16), Bad(u16),
 }
 
@@ -49,25 +70,62 @@ const _: (/* trait impls */) = {
         }
     }
 
+    impl std::fmt::Display for CloseFrame {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match &self.reason {
+                Some(reason) => write!(f, "{}: {reason}", self.code.as_u16()),
+                None         => write!(f, "{}", self.code.as_u16()),
+            }
+        }
+    }
+    impl std::error::Error for CloseFrame {}
+
+    impl From<CloseCode> for CloseFrame {
+        fn from(code: CloseCode) -> Self {
+            Self { code, reason: None }
+        }
+    }
+
     impl From<u16> for CloseCode {
         fn from(value: u16) -> Self {
             Self::from_u16(value)
         }
     }
-    impl Into<u16> for CloseCode {
-        fn into(self) -> u16 {
-            self.as_u16()
+    impl From<CloseCode> for u16 {
+        fn from(code: CloseCode) -> Self {
+            code.as_u16()
         }
     }
 };
 
+impl CloseFrame {
+    /// Maximum byte length of `reason` : 125 ( payload limit of control frames ) - 2 ( code )
+    pub const MAX_REASON_SIZE: usize = 123;
+
+    /// Get the close frame that has been sent to the peer due to the error, if any.
+    /// 
+    /// When the peer closed the connection without a close frame, this is
+    /// `CloseCode::Abnormal` ( 1006 ), which is only reported and never sent.
+    /// 
+    /// ```
+    /// # fn report(e: std::io::Error/* returned from `recv` */) {
+    /// if let Some(close) = mews::CloseFrame::from_error(&e) {
+    ///     eprintln!("connection closed with {close}")
+    /// }
+    /// # }
+    /// ```
+    pub fn from_error(error: &std::io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
 impl CloseCode {
     pub const fn from_u16(u16: u16) -> Self {
         match u16 {
             1000 => Self::Normal, 1001 => Self::Away,      1002 => Self::Protocol, 1003 => Self::Unsupported,
             1005 => Self::Status, 1006 => Self::Abnormal,  1007 => Self::Invalid,  1008 => Self::Policy,
             1009 => Self::Size,   1010 => Self::Extension, 1011 => Self::Error,    1012 => Self::Restart,
-            1013 => Self::Again,  1015 => Self::Tls,       1016..=2999 => Self::Reserved,
+            1013 => Self::Again,  1014 => Self::BadGateway, 1015 => Self::Tls,     1016..=2999 => Self::Reserved,
             3000..=3999 => Self::Iana(u16),
             4000..=4999 => Self::Library(u16),
             _ => Self::Bad(u16),
@@ -78,11 +136,79 @@ impl CloseCode {
             Self::Normal => 1000, Self::Away      => 1001, Self::Protocol => 1002, Self::Unsupported => 1003,
             Self::Status => 1005, Self::Abnormal  => 1006, Self::Invalid  => 1007, Self::Policy      => 1008,
             Self::Size   => 1009, Self::Extension => 1010, Self::Error    => 1011, Self::Restart     => 1012,
-            Self::Again  => 1013, Self::Tls       => 1015,
+            Self::Again  => 1013, Self::BadGateway => 1014, Self::Tls     => 1015,
             Self::Reserved => 1016,
             Self::Iana(code) | Self::Library(code) | Self::Bad(code) => *code,
         }
     }
+
+    /// name of the code in the IANA "WebSocket Close Code Number Registry",
+    /// e.g. `"Message Too Big"` for `Size` (1009).
+    pub const fn description(&self) -> &'static str {
+        match self {
+            Self::Normal      => "Normal Closure",
+            Self::Away        => "Going Away",
+            Self::Protocol    => "Protocol Error",
+            Self::Unsupported => "Unsupported Data",
+            Self::Status      => "No Status Received",
+            Self::Abnormal    => "Abnormal Closure",
+            Self::Invalid     => "Invalid Frame Payload Data",
+            Self::Policy      => "Policy Violation",
+            Self::Size        => "Message Too Big",
+            Self::Extension   => "Mandatory Extension",
+            Self::Error       => "Internal Error",
+            Self::Restart     => "Service Restart",
+            Self::Again       => "Try Again Later",
+            Self::BadGateway  => "Bad Gateway",
+            Self::Tls         => "TLS Handshake",
+            Self::Reserved    => "Reserved",
+            Self::Iana(_)     => "Registered",
+            Self::Library(_)  => "Private Use",
+            Self::Bad(_)      => "Invalid Code",
+        }
+    }
+
+    /// whether the code may be sent in a close frame.
+    /// 
+    /// `Status` (1005), `Abnormal` (1006) and `Tls` (1015) are only for
+    /// reporting a closure locally, and reserved or out-of-range codes are
+    /// never valid on the wire : sending a close frame of such a code fails
+    /// with `ErrorKind::InvalidInput`, and receiving one closes the connection
+    /// with `CloseCode::Protocol` (1002).
+    /// 
+    /// ```
+    /// # use mews::CloseCode;
+    /// assert!(CloseCode::Normal.is_allowed_to_send());
+    /// assert!(CloseCode::Library(4000).is_allowed_to_send());
+    /// assert!(!CloseCode::Abnormal.is_allowed_to_send());
+    /// ```
+    pub const fn is_allowed_to_send(&self) -> bool {
+        !matches!(self, Self::Status | Self::Abnormal | Self::Tls | Self::Reserved | Self::Bad(_))
+    }
+}
+
+#[cfg(feature="__runtime__")]
+impl CloseFrame {
+    /// `io::Error` closing the connection with `code` and `reason`
+    /// ( truncated to `MAX_REASON_SIZE` ).
+    pub(crate) fn error(code: CloseCode, reason: impl Into<String>) -> Error {
+        let mut reason = reason.into();
+        if reason.len() > Self::MAX_REASON_SIZE {
+            let mut end = Self::MAX_REASON_SIZE;
+            while !reason.is_char_boundary(end) {end -= 1}
+            reason.truncate(end);
+        }
+        Error::new(ErrorKind::InvalidData, Self { code, reason: Some(reason.into()) })
+    }
+
+    /// `io::Error` reporting that the peer closed the connection without
+    /// a close frame ( `1006 Abnormal`, never sent to the peer ).
+    pub(crate) fn abnormal() -> Error {
+        Error::new(ErrorKind::UnexpectedEof, Self {
+            code:   CloseCode::Abnormal,
+            reason: Some("Connection closed without a close frame".into())
+        })
+    }
 }
 
 #[cfg(feature="__runtime__")]
@@ -112,7 +238,7 @@ impl Message {
             }
             Message::Pong(mut bytes) => {
                 bytes.truncate(Self::PING_PONG_PAYLOAD_LIMIT);
-                (OpCode::Ping, bytes)
+                (OpCode::Pong, bytes)
             }
             Message::Close(close_frame) => {
                 let payload = close_frame
@@ -123,109 +249,479 @@ impl Message {
                     }).unwrap_or(Vec::new());
                 (OpCode::Close, payload)
             }
+            Message::Reserved { opcode, payload } => (OpCode::Reserved(opcode), payload),
         };
 
         Frame { is_final:true, opcode, payload }
     }
 
+    /// Frames of the message, a data message larger than `Config::max_frame_size`
+    /// being fragmented into frames within the limit.
+    pub(crate) fn into_frames(self, config: &Config) -> Vec<Frame> {
+        let frame = self.into_frame();
+        match frame.opcode {
+            OpCode::Text | OpCode::Binary => Frame::fragments(frame.opcode, frame.payload, true, true, config),
+            _ => vec![frame]
+        }
+    }
+
+    /// Encode the frames of the message to the end of `buf`, returning the number of bytes.
+    #[inline]
+    pub(crate) fn encode(self, buf: &mut Vec<u8>, config: &Config) -> usize {
+        self.into_frames(config).into_iter()
+            .map(|frame| frame.encode_unmasked(buf))
+            .sum()
+    }
+
+    #[inline]
+    pub(crate) fn from_control_frame(frame: Frame) -> Result<Self, Error> {
+        match frame.opcode {
+            OpCode::Ping => {
+                let payload = frame.payload;
+                (payload.len() <= Self::PING_PONG_PAYLOAD_LIMIT).then_some(())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Incoming ping payload is too large"))?;
+                Ok(Message::Ping(payload))
+            }
+            OpCode::Pong => {
+                let payload = frame.payload;
+                (payload.len() <= Self::PING_PONG_PAYLOAD_LIMIT)
+                    .then_some(Message::Pong(payload))
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Incoming pong payload is too large"))
+            }
+            OpCode::Close => match &*frame.payload {
+                [] => Ok(Message::Close(None)),
+                [_] => Err(CloseFrame::error(CloseCode::Protocol, "Close frame payload of 1 byte")),
+                [c1, c2, rem @ ..] => {
+                    let code   = CloseCode::from_bytes([*c1, *c2]);
+                    if !code.is_allowed_to_send() {
+                        return Err(CloseFrame::error(CloseCode::Protocol, format!("Close code {} is not allowed on the wire", code.as_u16())))
+                    }
+                    let reason = match rem {
+                        [] => None,
+                        _  => Some(String::from_utf8(rem.to_vec())
+                            .map_err(|e| CloseFrame::error(CloseCode::Invalid, format!("Close reason is not valid UTF-8: {e}")))?
+                            .into())
+                    };
+                    Ok(Message::Close(Some(CloseFrame { code, reason })))
+                }
+            }
+            _ => unreachable!("`from_control_frame` called with a data frame")
+        }
+    }
+
+    #[inline]
+    fn from_data_payload(opcode: OpCode, payload: Vec<u8>) -> Result<Self, Error> {
+        match opcode {
+            OpCode::Text => String::from_utf8(payload)
+                .map(Message::Text)
+                .map_err(|e| CloseFrame::error(CloseCode::Invalid, format!("Text message is not valid UTF-8: {e}"))),
+            OpCode::Binary => Ok(Message::Binary(payload)),
+            _ => unreachable!("`from_data_payload` called with a non-data opcode")
+        }
+    }
+}
+
+/// Reader of `Message`s on `FrameReader`, keeping the reassembly state of a
+/// fragmented message.
+/// 
+/// Every state is kept in `self` between the awaits, so a cancelled `read`
+/// ( e.g. by a timeout ) is resumed by the next `read` without losing
+/// any partially-received frame or fragment.
+#[cfg(feature="__runtime__")]
+pub(crate) struct MessageReader {
+    frames:     FrameReader,
+    fragmented: Option<Fragmented>,
+}
+#[cfg(feature="__runtime__")]
+struct Fragmented {
+    opcode:  OpCode,
+    payload: Vec<u8>,
+    info:    RecvInfo,
+    charge:  Option<Charge>,
+}
+/// bytes charged on `Config::reassembly_budget`, released on drop
+#[cfg(feature="__runtime__")]
+struct Charge {
+    budget: ReassemblyBudget,
+    size:   usize,
+}
+#[cfg(feature="__runtime__")]
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.release(self.size);
+    }
+}
+
+#[cfg(feature="__runtime__")]
+impl MessageReader {
+    pub(crate) fn new() -> Self {
+        Self { frames: FrameReader::new(), fragmented: None }
+    }
+
     #[inline]
-    pub(crate) async fn write(self,
-        stream: &mut (impl Write + Unpin),
+    pub(crate) fn has_buffered_frame(&self, config: &Config) -> bool {
+        self.frames.has_buffered_frame(config)
+    }
+
+    #[inline]
+    pub(crate) fn received_unmasked(&self) -> &std::sync::Arc<std::sync::atomic::AtomicBool> {
+        self.frames.received_unmasked()
+    }
+
+    #[inline]
+    pub(crate) fn activity(&self) -> &std::sync::Arc<crate::frame::Activity> {
+        self.frames.activity()
+    }
+
+    #[inline]
+    pub(crate) fn recycle(&mut self, payload: Vec<u8>) {
+        self.frames.recycle(payload)
+    }
+
+    /// Read a raw frame bypassing the reassembly, e.g. to forward it as it is.
+    #[inline]
+    pub(crate) async fn read_frame(&mut self,
+        stream: &mut (impl Read + Unpin),
         config: &Config,
-    ) -> Result<usize, Error> {
-        self.into_frame().write_unmasked(stream, config).await
+    ) -> Result<Option<Frame>, Error> {
+        self.frames.read_frame(stream, config, None).await
     }
-    
-    /// Read a `Message` from a WebSocket connection.
-    pub(crate) async fn read_from(
+
+    /// Read a `Message` together with its `RecvInfo`.
+    /// 
+    /// Control frames interleaved in a fragmented message are returned
+    /// immediately, and the reassembly continues in the next `read`.
+    pub(crate) async fn read(&mut self,
         stream: &mut (impl Read + Unpin),
         config: &Config,
-    ) -> Result<Option<Self>, Error> {
-        let Some(first_frame) = Frame::read_from(stream, config).await? else {
-            return Ok(None)
-        };
+    ) -> Result<Option<(Message, RecvInfo)>, Error> {
+        loop {
+            let fragmented = self.fragmented.as_ref().map(|f| (f.opcode, f.payload.len()));
+            let Some(frame) = self.frames.read_frame(stream, config, fragmented).await? else {
+                return match self.fragmented {
+                    None    => Ok(None),
+                    Some(_) => Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the middle of a fragmented message"))
+                }
+            };
 
-        match &first_frame.opcode {
-            OpCode::Text => {
-                let mut payload = String::from_utf8(first_frame.payload)
-                    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Text frame's payload is not valid UTF-8: {e}")))?;
-                if !first_frame.is_final {
-                    while let Ok(Some(next_frame)) = Frame::read_from(stream, config).await {
-                        if next_frame.opcode != OpCode::Continue {
-                            return Err(Error::new(ErrorKind::InvalidData, "Expected continue frame"));
-                        }
-                        payload.push_str(std::str::from_utf8(&next_frame.payload)
-                            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Text frame's payload is not valid UTF-8: {e}")))?
-                        );
-                        if next_frame.is_final {
-                            break
-                        }
+            match frame.opcode {
+                OpCode::Text | OpCode::Binary => {
+                    if self.fragmented.is_some() {
+                        return Err(CloseFrame::error(CloseCode::Protocol, "Expected continue frame, found a new data frame"))
                     }
-                }
 
-                if let Some(limit) = &config.max_message_size {
-                    (&payload.len() <= limit).then_some(())
-                        .ok_or_else(|| Error::new(
-                            ErrorKind::InvalidData,
-                            format!("Incoming message (size: {}) is larger than limit ({})", payload.len(), *limit)
-                        ))?;
+                    check_message_size(frame.opcode, frame.payload.len(), config)?;
+                    let info = RecvInfo { fragments: 1 };
+                    if frame.is_final {
+                        return Ok(Some((Message::from_data_payload(frame.opcode, frame.payload)?, info)))
+                    }
+                    let mut charge = None;
+                    charge_reassembly(&mut charge, frame.payload.len(), config)?;
+                    self.fragmented = Some(Fragmented { opcode: frame.opcode, payload: frame.payload, info, charge });
                 }
 
-                Ok(Some(Message::Text(payload)))
-            }
-            OpCode::Binary => {
-                let mut payload = first_frame.payload;
-                if !first_frame.is_final {
-                    while let Ok(Some(mut next_frame)) = Frame::read_from(stream, config).await {
-                        if next_frame.opcode != OpCode::Continue {
-                            return Err(Error::new(ErrorKind::InvalidData, "Expected continue frame"));
-                        }
-                        payload.append(
-                            &mut next_frame.payload
-                        );
-                        if next_frame.is_final {
-                            break
-                        }
+                OpCode::Continue => {
+                    let Some(fragmented) = &mut self.fragmented else {
+                        return Err(CloseFrame::error(CloseCode::Protocol, "Unexpected continue frame without a preceding data frame"))
+                    };
+
+                    charge_reassembly(&mut fragmented.charge, frame.payload.len(), config)?;
+                    fragmented.payload.extend_from_slice(&frame.payload);
+                    fragmented.info.fragments += 1;
+                    check_message_size(fragmented.opcode, fragmented.payload.len(), config)?;
+
+                    if frame.is_final {
+                        let Fragmented { opcode, payload, info, .. } = self.fragmented.take().unwrap();
+                        return Ok(Some((Message::from_data_payload(opcode, payload)?, info)))
                     }
                 }
 
-                if let Some(limit) = &config.max_message_size {
-                    (&payload.len() <= limit).then_some(())
-                        .ok_or_else(|| Error::new(
-                            ErrorKind::InvalidData,
-                            format!("Incoming message (size: {}) is larger than limit ({})", payload.len(), *limit)
-                        ))?;
+                OpCode::Ping | OpCode::Pong | OpCode::Close => {
+                    return Ok(Some((Message::from_control_frame(frame)?, RecvInfo { fragments: 1 })))
                 }
 
-                Ok(Some(Message::Binary(payload)))
+                OpCode::Reserved(opcode) => {
+                    check_message_size(frame.opcode, frame.payload.len(), config)?;
+                    return Ok(Some((Message::Reserved { opcode, payload: frame.payload }, RecvInfo { fragments: 1 })))
+                }
             }
+        }
+    }
+}
 
-            OpCode::Ping => {
-                let payload = first_frame.payload;
-                (payload.len() <= Self::PING_PONG_PAYLOAD_LIMIT).then_some(())
-                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Incoming ping payload is too large"))?;
-                Ok(Some(Message::Ping(payload)))
-            }
-            OpCode::Pong => {
-                let payload = first_frame.payload;
-                (payload.len() <= Self::PING_PONG_PAYLOAD_LIMIT)
-                    .then_some(Some(Message::Pong(payload)))
-                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Incoming pong payload is too large"))
-            }
+#[cfg(feature="__runtime__")]
+#[inline]
+pub(crate) fn check_message_size(opcode: OpCode, size: usize, config: &Config) -> Result<(), Error> {
+    let limit = match opcode {
+        OpCode::Text   => config.max_text_message_size.or(config.max_message_size),
+        OpCode::Binary => config.max_binary_message_size.or(config.max_message_size),
+        _ => config.max_message_size
+    };
+    match limit {
+        Some(limit) if size > limit => Err(CloseFrame::error(
+            CloseCode::Size,
+            format!("message {} exceeds limit {}", human_size(size), human_size(limit))
+        )),
+        _ => Ok(())
+    }
+}
 
-            OpCode::Close => {
-                let payload = first_frame.payload;
-                Ok(Some(Message::Close(
-                    (! payload.is_empty()).then(|| {
-                        let (code_bytes, rem) = payload.split_at(2);
-                        let code   = CloseCode::from_bytes(unsafe {(code_bytes.as_ptr() as *const [u8; 2]).read()});
-                        let reason = (! rem.is_empty()).then(|| String::from_utf8(rem.to_vec()).unwrap().into());
-                        CloseFrame { code, reason }
-                    })
-                )))
-            }
+#[cfg(feature="__runtime__")]
+fn charge_reassembly(charge: &mut Option<Charge>, size: usize, config: &Config) -> Result<(), Error> {
+    let Some(budget) = &config.reassembly_budget else {return Ok(())};
+    if !budget.try_charge(size) {
+        return Err(CloseFrame::error(
+            CloseCode::Size,
+            format!("reassembly budget {} is exhausted", human_size(budget.limit()))
+        ))
+    }
+    match charge {
+        Some(charge) => charge.size += size,
+        None => *charge = Some(Charge { budget: budget.clone(), size }),
+    }
+    Ok(())
+}
 
-            OpCode::Continue => Err(Error::new(ErrorKind::InvalidData, "Unexpected continue frame"))
-        }
+#[cfg(feature="__runtime__")]
+pub(crate) fn human_size(size: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let (mut value, mut unit) = (size as f64, 0);
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit  += 1;
     }
+
+    if value.fract() == 0. {
+        format!("{value}{}", UNITS[unit])
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_new_data_frame_in_fragmented_message() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = [masked(0x01/* non-final Text */, b"Hello, "), masked(0x82/* final Binary */, b"World!")].concat();
+    let error = block_on(MessageReader::new().read(&mut &bytes[..], &Config::default())).unwrap_err();
+    assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Protocol));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_empty_final_continuation() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = [masked(0x01/* non-final Text */, b"Hello, World!"), masked(0x80/* final Continue */, b"")].concat();
+    let (message, _) = block_on(MessageReader::new().read(&mut &bytes[..], &Config::default())).unwrap().unwrap();
+    assert!(matches!(message, Message::Text(text) if text == "Hello, World!"));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_close_without_payload() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = [masked(0x88/* Close */, b""), masked(0x88, &CloseCode::Normal.into_bytes())].concat();
+    let (mut stream, mut reader, config) = (&bytes[..], MessageReader::new(), Config::default());
+
+    let (message, _) = block_on(reader.read(&mut stream, &config)).unwrap().unwrap();
+    assert!(matches!(message, Message::Close(None)));
+    let (message, _) = block_on(reader.read(&mut stream, &config)).unwrap().unwrap();
+    assert!(matches!(message, Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: None }))));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_fragment_by_max_frame_size() {
+    use crate::frame::Decoded;
+
+    let config = Config { max_frame_size: Some(4), accept_unmasked_frames: true, ..Default::default() };
+    let mut bytes = Vec::new();
+    Message::Text("Hello, World!".into()).encode(&mut bytes, &config);
+
+    let mut frames = Vec::new();
+    let mut rem = &bytes[..];
+    while let Decoded::Frame(frame, size) = Frame::decode(rem, &config).unwrap() {
+        assert!(frame.payload.len() <= 4);
+        frames.push(frame);
+        rem = &rem[size..];
+    }
+    assert!(rem.is_empty());
+    assert_eq!(frames.len(), 4);
+    assert_eq!(frames.iter().map(|f| (f.is_final, f.opcode)).collect::<Vec<_>>(), [
+        (false, OpCode::Text), (false, OpCode::Continue), (false, OpCode::Continue), (true, OpCode::Continue)
+    ]);
+    assert_eq!(frames.into_iter().flat_map(|f| f.payload).collect::<Vec<_>>(), b"Hello, World!");
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_reassembly_budget() {
+    use crate::__test__::{block_on, masked};
+
+    let budget = ReassemblyBudget::new(8);
+    let config = Config { reassembly_budget: Some(budget.clone()), ..Default::default() };
+
+    let (bytes, mut holding) = (masked(0x01/* non-final Text */, b"Hello"), MessageReader::new());
+    assert!(block_on(holding.read(&mut &bytes[..], &config)).is_err()/* EOF in the fragmented message */);
+    assert_eq!(budget.used(), 5);
+
+    let error = block_on(MessageReader::new().read(&mut &bytes[..], &config)).unwrap_err();
+    assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Size));
+    assert_eq!(budget.used(), 5);
+
+    drop(holding);
+    assert_eq!(budget.used(), 0);
+
+    let bytes = [masked(0x01, b"Hello"), masked(0x80/* final Continue */, b"!")].concat();
+    let (message, _) = block_on(MessageReader::new().read(&mut &bytes[..], &config)).unwrap().unwrap();
+    assert!(matches!(message, Message::Text(text) if text == "Hello!"));
+    assert_eq!(budget.used(), 0);
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_reject_oversized_from_header() {
+    use crate::__test__::{block_on, masked};
+
+    /* only the header of a 1 GiB binary frame arrives */
+    let header = [0x82, 0x80 | 127, 0, 0, 0, 0, 0x40, 0, 0, 0, 0x37, 0xfa, 0x21, 0x3d];
+    let config = Config { max_frame_size: None, ..Default::default() };
+    let error = block_on(MessageReader::new().read(&mut &header[..], &config)).unwrap_err();
+    assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Size));
+
+    /* a continuation frame's header making the message over the limit */
+    let config = Config { max_message_size: Some(8), ..Default::default() };
+    let bytes = [masked(0x01/* non-final Text */, b"Hello"), masked(0x80, b"World")[..6].to_vec()].concat();
+    let error = block_on(MessageReader::new().read(&mut &bytes[..], &config)).unwrap_err();
+    assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Size));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_max_text_message_size() {
+    use crate::__test__::{block_on, masked};
+
+    let config = Config { max_text_message_size: Some(4), max_message_size: Some(8), ..Default::default() };
+    for bytes in [masked(0x81, b"Hello"), [masked(0x01, b"Hel"), masked(0x80, b"lo")].concat()] {
+        let error = block_on(MessageReader::new().read(&mut &bytes[..], &config)).unwrap_err();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Size));
+    }
+
+    /* binary messages are still limited by `max_message_size` */
+    let bytes = masked(0x82, b"Hello");
+    assert!(block_on(MessageReader::new().read(&mut &bytes[..], &config)).is_ok());
+    let bytes = masked(0x82, b"Hello, world");
+    assert!(block_on(MessageReader::new().read(&mut &bytes[..], &config)).is_err());
+
+    /* overriding to be larger than `max_message_size` */
+    let config = Config { max_text_message_size: Some(16), max_message_size: Some(8), ..Default::default() };
+    let bytes = [masked(0x01, b"Hello, "), masked(0x80, b"world")].concat();
+    let (message, _) = block_on(MessageReader::new().read(&mut &bytes[..], &config)).unwrap().unwrap();
+    assert!(matches!(message, Message::Text(text) if text == "Hello, world"));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_max_binary_message_size() {
+    use crate::__test__::{block_on, masked};
+
+    let config = Config { max_binary_message_size: Some(4), max_message_size: Some(8), ..Default::default() };
+    for bytes in [masked(0x82, b"Hello"), [masked(0x02, b"Hel"), masked(0x80, b"lo")].concat()] {
+        let error = block_on(MessageReader::new().read(&mut &bytes[..], &config)).unwrap_err();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Size));
+    }
+
+    /* text messages are still limited by `max_message_size` */
+    let bytes = masked(0x81, b"Hello");
+    assert!(block_on(MessageReader::new().read(&mut &bytes[..], &config)).is_ok());
+    let bytes = masked(0x81, b"Hello, world");
+    assert!(block_on(MessageReader::new().read(&mut &bytes[..], &config)).is_err());
+
+    /* overriding to be larger than `max_message_size` */
+    let config = Config { max_binary_message_size: Some(16), max_message_size: Some(8), ..Default::default() };
+    let bytes = [masked(0x02, b"Hello, "), masked(0x80, b"world")].concat();
+    let (message, _) = block_on(MessageReader::new().read(&mut &bytes[..], &config)).unwrap().unwrap();
+    assert!(matches!(message, Message::Binary(bytes) if bytes == b"Hello, world"));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_receive_unsendable_close_code() {
+    use crate::__test__::{block_on, masked};
+
+    for code in [1005u16, 1006, 1015] {
+        let bytes = masked(0x88, &code.to_be_bytes());
+        let error = block_on(MessageReader::new().read(&mut &bytes[..], &Config::default())).unwrap_err();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Protocol));
+    }
+}
+
+#[cfg(test)]
+#[test] fn test_close_code() {
+    for code in (1000..=1015).filter(|code| *code != 1004/* reserved */) {
+        let close_code = CloseCode::from_u16(code);
+        assert!(!matches!(close_code, CloseCode::Bad(_) | CloseCode::Reserved), "{code} is registered");
+        assert_eq!(close_code.as_u16(), code);
+    }
+    assert_eq!(CloseCode::from_u16(1014), CloseCode::BadGateway);
+    assert_eq!(CloseCode::BadGateway.description(), "Bad Gateway");
+    assert!(CloseCode::BadGateway.is_allowed_to_send());
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_receive_bad_gateway() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = masked(0x88, &1014u16.to_be_bytes());
+    let (message, _) = block_on(MessageReader::new().read(&mut &bytes[..], &Config::default())).unwrap().unwrap();
+    assert!(matches!(message, Message::Close(Some(CloseFrame { code: CloseCode::BadGateway, reason: None }))));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_orphan_continuation() {
+    use crate::__test__::{block_on, masked};
+
+    for first in [0x00/* non-final */, 0x80/* final */] {
+        let bytes = masked(first, b"orphan");
+        let error = block_on(MessageReader::new().read(&mut &bytes[..], &Config::default())).unwrap_err();
+        assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Protocol));
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_activity() {
+    use crate::__test__::{block_on, masked};
+
+    let bytes = masked(0x81, b"active");
+    let reader = &mut MessageReader::new();
+    let activity = reader.activity().clone();
+    assert_eq!(activity.last_activity(), activity.connected_at());
+
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    block_on(reader.read(&mut &bytes[..], &Config::default())).unwrap().unwrap();
+    assert!(activity.last_activity() >= activity.connected_at() + std::time::Duration::from_millis(1));
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_mixed_masking() {
+    use crate::__test__::{block_on, masked};
+
+    let unmasked = |payload: &[u8]| [&[0x81, payload.len() as u8], payload].concat();
+    let bytes = [masked(0x81, b"one"), unmasked(b"two"), masked(0x81, b"three"), unmasked(b"four")].concat();
+    let (mut stream, mut reader) = (&bytes[..], MessageReader::new());
+    let config = Config { accept_unmasked_frames: true, ..Default::default() };
+
+    for expected in ["one", "two", "three", "four"] {
+        let (message, _) = block_on(reader.read(&mut stream, &config)).unwrap().unwrap();
+        assert!(matches!(message, Message::Text(text) if text == expected));
+    }
+}
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_received_unmasked() {
+    use {crate::__test__::{block_on, masked}, std::sync::atomic::Ordering};
+
+    let bytes = [masked(0x81, b"masked"), vec![0x81, 0x08, b'u', b'n', b'm', b'a', b's', b'k', b'e', b'd']].concat();
+    let (mut stream, mut reader) = (&bytes[..], MessageReader::new());
+    let config = Config { accept_unmasked_frames: true, ..Default::default() };
+
+    block_on(reader.read(&mut stream, &config)).unwrap().unwrap();
+    assert!(!reader.received_unmasked().load(Ordering::Relaxed));
+    block_on(reader.read(&mut stream, &config)).unwrap().unwrap();
+    assert!(reader.received_unmasked().load(Ordering::Relaxed));
 }
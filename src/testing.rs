@@ -0,0 +1,182 @@
+//! Utilities to test WebSocket handlers in isolation, without any IO.
+
+use crate::{Config, Connection, Message, BoxedConnection};
+use crate::message::MessageReader;
+use crate::runtime::{AsyncRead, AsyncWrite};
+use std::{pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}, io};
+
+/// Create a `Connection` receiving `inbound` in order as from a client,
+/// and capturing what's sent to it into `OutboundCapture`.
+///
+/// After `inbound`, the client disconnects : `recv` returns an error of
+/// `CloseCode::Abnormal` (1006), unless the script ends with a close message.
+///
+/// **note** : Handlers to be tested in this way take `Connection<BoxedConnection>`
+/// ( or are generic over the underlying connection ) instead of the default
+/// `Connection<TcpStream>`.
+///
+/// *example.rs*
+/// ```
+/// # use mews::{Connection, Message, BoxedConnection};
+/// #
+/// async fn echo(conn: Connection<BoxedConnection>) {
+///     while let Ok(Some(Message::Text(text))) = conn.recv().await {
+///         conn.send(text).await.unwrap()
+///     }
+/// }
+///
+/// # async fn __() {
+/// let (conn, outbound) = mews::testing::scripted(vec![
+///     Message::Text("hello".into()),
+///     Message::Close(None),
+/// ]);
+/// echo(conn).await;
+/// assert!(matches!(&outbound.messages()[..], [Message::Text(text)] if text == "hello"));
+/// # }
+/// ```
+pub fn scripted(inbound: Vec<Message>) -> (Connection<BoxedConnection>, OutboundCapture) {
+    let config = Config::default();
+    let mut bytes = Vec::new();
+    for message in inbound {
+        for frame in message.into_frames(&config) {
+            frame.encode_masked([0x37, 0xfa, 0x21, 0x3d], &mut bytes);
+        }
+    }
+
+    let outbound = OutboundCapture(Arc::new(Mutex::new(Vec::new())));
+    let (conn, _closer) = Connection::new(
+        BoxedConnection::new(Script { inbound: bytes, read: 0, outbound: outbound.clone() }),
+        config
+    );
+    (conn, outbound)
+}
+
+/// Bytes sent to the connection created by [`scripted`].
+#[derive(Clone, Debug)]
+pub struct OutboundCapture(Arc<Mutex<Vec<u8>>>);
+impl OutboundCapture {
+    /// the raw bytes sent so far
+    pub fn bytes(&self) -> Vec<u8> {
+        self.lock().clone()
+    }
+
+    /// the messages sent so far, in order
+    ///
+    /// ## Panics
+    ///
+    /// This panics if the sent bytes are not valid frames, which would be a bug of mews.
+    pub fn messages(&self) -> Vec<Message> {
+        let bytes = self.bytes();
+        let config = Config {
+            accept_unmasked_frames: true,
+            max_message_size:       None,
+            max_frame_size:         None,
+            ..Default::default()
+        };
+
+        let (mut bytes, mut reader, mut messages) = (&bytes[..], MessageReader::new(), Vec::new());
+        while let Some((message, _)) = block_on(reader.read(&mut bytes, &config)).expect("invalid frames are sent") {
+            messages.push(message)
+        }
+        messages
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// drive a future on in-memory IO ( always ready ) to completion
+fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+    let mut future = std::pin::pin!(future);
+    let mut cx = Context::from_waker(std::task::Waker::noop());
+    loop {
+        if let Poll::Ready(t) = future.as_mut().poll(&mut cx) {
+            return t
+        }
+    }
+}
+
+struct Script {
+    inbound:  Vec<u8>,
+    read:     usize,
+    outbound: OutboundCapture,
+}
+impl Script {
+    fn read_into(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.inbound.len() - self.read);
+        buf[..n].copy_from_slice(&self.inbound[self.read..self.read + n]);
+        self.read += n;
+        n
+    }
+}
+
+#[cfg(any(feature="rt_tokio", feature="rt_nio"))]
+const _: () = {
+    impl AsyncRead for Script {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let n = self.get_mut().read_into(buf.initialize_unfilled());
+            buf.advance(n);
+            Poll::Ready(Ok(()))
+        }
+    }
+    impl AsyncWrite for Script {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.outbound.lock().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+};
+
+#[cfg(any(feature="rt_async-std", feature="rt_smol", feature="rt_glommio"))]
+const _: () = {
+    impl AsyncRead for Script {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(self.get_mut().read_into(buf)))
+        }
+    }
+    impl AsyncWrite for Script {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.outbound.lock().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+};
+
+#[cfg(all(test, feature="__runtime__"))]
+#[test] fn test_scripted() {
+    use crate::{CloseCode, CloseFrame};
+
+    let (conn, outbound) = scripted(vec![
+        Message::Text("hello".into()),
+        Message::Binary(vec![0; 1 << 16]),
+        Message::Ping(b"ping".to_vec()),
+    ]);
+    let error = block_on(async {
+        loop {
+            match conn.recv().await {
+                Ok(Some(Message::Text(text))) => conn.send(format!("echo: {text}")).await.unwrap(),
+                Ok(_) => continue,
+                Err(e) => break e
+            }
+        }
+    });
+    assert_eq!(CloseFrame::from_error(&error).map(|f| &f.code), Some(&CloseCode::Abnormal));
+
+    assert!(matches!(
+        &outbound.messages()[..],
+        [Message::Text(text), Message::Pong(pong)] if text == "echo: hello" && pong == b"ping"
+    ));
+}
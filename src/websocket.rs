@@ -1,15 +1,131 @@
 use crate::runtime;
-use crate::message::{CloseFrame, CloseCode};
-use crate::connection::{UnderlyingConnection, Connection};
+use crate::message::{Message, CloseFrame, CloseCode};
+use crate::connection::{UnderlyingConnection, Connection, ConnectionInfo};
 
-/// **note** : currently, subprotocols via `Sec-WebSocket-Protocol` is not supported.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Config {
     pub write_buffer_size:      usize,
     pub max_write_buffer_size:  usize,
+    /// accept frames from the client without masking, which RFC 6455 requires.
+    /// 
+    /// This is decided per frame by its mask bit : a masked frame is still
+    /// unmasked ( unless `skip_unmasking` ), and an unmasked one is taken as
+    /// it is, so a client may mix them.
     pub accept_unmasked_frames: bool,
+    /// along with `accept_unmasked_frames`, take the payload of masked frames
+    /// as it is without unmasking, saving the XOR per byte.
+    /// 
+    /// **UNSAFE for public endpoints** : This is a performance escape hatch for
+    /// trusted links whose clients send unmasked frames. A frame that's actually
+    /// masked is delivered with its payload still masked, i.e. corrupted
+    /// ( and a text frame likely fails UTF-8 validation ). Ignored when
+    /// `accept_unmasked_frames` is disabled.
+    pub skip_unmasking:         bool,
     pub max_message_size:       Option<usize>,
+    /// limit of a text message's size taking precedence over `max_message_size`,
+    /// e.g. to keep a text control channel small while allowing large binary
+    /// uploads. An incoming message over it closes the connection with
+    /// `CloseCode::Size` (1009). `None` falls back to `max_message_size`.
+    pub max_text_message_size:  Option<usize>,
+    /// same as `max_text_message_size` for binary messages.
+    pub max_binary_message_size: Option<usize>,
+    /// limit of a frame's payload size : an incoming frame over it closes the
+    /// connection with `CloseCode::Size` (1009), and an outgoing data message
+    /// over it is sent fragmented into frames within it.
     pub max_frame_size:         Option<usize>,
+    /// read larger chunks from the underlying connection into a buffer and
+    /// decode multiple frames from it, cutting syscalls for small-frame bursts.
+    /// 
+    /// When disabled, exactly the bytes of each frame part are read.
+    pub read_ahead:             bool,
+    /// reject frames whose payload length uses a larger encoding than
+    /// necessary (e.g. 16-bit length for 5 bytes), closing with
+    /// `CloseCode::Protocol` (1002).
+    /// 
+    /// Disabled by default for interoperability.
+    pub strict_length_encoding: bool,
+    /// what to do on a frame with a reserved opcode ( 0x3-0x7, 0xb-0xf ).
+    pub on_unknown_opcode:      UnknownOpcode,
+    /// discard incoming messages other than the peer's close frame after we
+    /// sent a close frame, as RFC 6455 allows. When disabled, they're delivered
+    /// until the peer's close frame.
+    pub discard_after_close:    bool,
+    /// answer only the last of `Ping`s arriving back-to-back by a single `Pong`
+    /// in `Connection::recv`, as RFC 6455 allows, instead of one per `Ping`.
+    /// 
+    /// Only the `Ping`s already in the read buffer together are coalesced,
+    /// so this takes effect with `read_ahead`.
+    pub coalesce_pongs:         bool,
+    /// drop outgoing `Ping`s sent within this interval since the last one,
+    /// limiting a ping flood. `None` sends every `Ping`.
+    /// 
+    /// **note** : A dropped `Ping` is not reported as an error, and so its `Pong`
+    /// never arrives (e.g. [`Pinger`](crate::Pinger)'s `Pong` future is left pending).
+    pub min_ping_interval:      Option<std::time::Duration>,
+    /// limit of bytes per second written to the connection, pacing writes
+    /// by a token bucket holding up to a second's worth instead of dropping
+    /// anything. `None` means no limit.
+    /// 
+    /// **note** : This paces writing the buffered bytes to the connection,
+    /// not buffering them : `write` buffers without waiting until it drains
+    /// the buffer over `write_buffer_size`, and `send` or `flush` waits until
+    /// all of the buffer is written. Control frames, e.g. `Pong`s and the close
+    /// frame, share the limit with data messages.
+    pub max_send_bytes_per_second: Option<u64>,
+    /// close the connection with `CloseCode::Policy` (1008) when a frame
+    /// is received slower than this, e.g. by slow-loris-style clients
+    /// dripping bytes to keep connections open. `None` means no limit.
+    /// 
+    /// **note** : Only a partially-received frame is measured, since its
+    /// first byte arrived, so idle connections between frames aren't affected.
+    /// A client stopping in the middle of a frame is also closed, once the
+    /// bytes received so far fall below the rate.
+    pub min_read_rate:          Option<ReadRate>,
+    /// `SO_LINGER` set to the underlying connection when it's `TcpStream`
+    /// of the selected runtime. `None` leaves it as it is.
+    /// 
+    /// **note** :
+    /// 
+    /// * `Some(Some(Duration::ZERO))` resets the connection on close instead of
+    ///   the normal shutdown, avoiding `TIME_WAIT` but possibly discarding unsent data.
+    /// * With non-zero duration, closing the socket may block the thread until
+    ///   unsent data is sent or the duration elapses, depending on the platform.
+    /// * This is a no-op on `rt_nio`, and for non-`TcpStream` connections.
+    pub tcp_linger:             Option<Option<std::time::Duration>>,
+    /// `SO_RCVBUF` / `SO_SNDBUF` set to the underlying connection when it's
+    /// `TcpStream` of the selected runtime. `None` leaves it as it is.
+    /// 
+    /// **note** :
+    /// 
+    /// * The OS may clamp the requested size, e.g. by `net.core.rmem_max` /
+    ///   `net.core.wmem_max` on Linux, where the kernel also doubles the value
+    ///   for its bookkeeping.
+    /// * This is a no-op on `rt_nio`, and for non-`TcpStream` connections.
+    pub socket_recv_buffer:     Option<usize>,
+    /// see `socket_recv_buffer`.
+    pub socket_send_buffer:     Option<usize>,
+    /// close frame sent when the handler finishes without closing the connection.
+    /// 
+    /// `None` means `CloseCode::Normal` (1000) without reason.
+    pub default_close:          Option<CloseFrame>,
+    /// whether to await the peer's close frame after sending the close frame
+    /// when the handler finishes, i.e. the closing by `Closer`.
+    pub handler_exit_close:     HandlerExitClose,
+    /// rewrite every outgoing close frame with a status before it's encoded,
+    /// e.g. to strip sensitive reasons, including the ones mews sends by itself
+    /// on errors. The rewritten frame is still validated as the original.
+    pub on_close_send:          Option<CloseHook>,
+    /// budget shared by connections for their fragmented messages under
+    /// reassembly : a fragment exceeding it closes the connection with
+    /// `CloseCode::Size` (1009). `None` means no global limit.
+    pub reassembly_budget:      Option<ReassemblyBudget>,
+    /// with the `tracing` feature, log a frame rejected as invalid at debug level :
+    /// its header (FIN, RSV bits, opcode, mask, length) and up to this many leading
+    /// bytes of its payload, unmasked. `Some(0)` logs the header only, and `None`
+    /// logs nothing, e.g. not to leak payloads of sensitive applications.
+    /// 
+    /// This is a no-op without the `tracing` feature.
+    pub log_invalid_frame:      Option<usize>,
 }
 const _: () = {
     impl Default for Config {
@@ -18,18 +134,298 @@ const _: () = {
                 write_buffer_size:      128 * 1024, // 128 KiB
                 max_write_buffer_size:  usize::MAX,
                 accept_unmasked_frames: false,
+                skip_unmasking:         false,
                 max_message_size:       Some(64 << 20),
+                max_text_message_size:  None,
+                max_binary_message_size: None,
                 max_frame_size:         Some(16 << 20),
+                read_ahead:             false,
+                strict_length_encoding: false,
+                on_unknown_opcode:      UnknownOpcode::Close,
+                discard_after_close:    true,
+                coalesce_pongs:         false,
+                min_ping_interval:      None,
+                max_send_bytes_per_second: None,
+                min_read_rate:          None,
+                tcp_linger:             None,
+                socket_recv_buffer:     None,
+                socket_send_buffer:     None,
+                default_close:          None,
+                handler_exit_close:     HandlerExitClose::Immediate,
+                on_close_send:          None,
+                reassembly_budget:      None,
+                log_invalid_frame:      Some(16),
             }
         }
     }
 };
 
+/// Byte budget shared by connections for reassembling fragmented messages,
+/// see `Config::reassembly_budget`.
+/// 
+/// Each connection is charged for the fragments it holds, and the charge is
+/// released when its message is completed or the connection is dropped.
+/// 
+/// *example.rs*
+/// ```
+/// # use mews::{Config, ReassemblyBudget};
+/// let budget = ReassemblyBudget::new(256 << 20/* 256 MiB over all connections */);
+/// 
+/// let config = Config {
+///     reassembly_budget: Some(budget.clone()),
+///     ..Default::default()
+/// };
+/// /* `WebSocketContext::new(..).with(config.clone())` for each connection */
+/// ```
+#[derive(Clone)]
+pub struct ReassemblyBudget(std::sync::Arc<Budget>);
+struct Budget {
+    limit: usize,
+    used:  std::sync::atomic::AtomicUsize,
+}
+impl ReassemblyBudget {
+    pub fn new(limit: usize) -> Self {
+        Self(std::sync::Arc::new(Budget { limit, used: std::sync::atomic::AtomicUsize::new(0) }))
+    }
+
+    pub fn limit(&self) -> usize {
+        self.0.limit
+    }
+
+    /// bytes currently charged by connections
+    pub fn used(&self) -> usize {
+        self.0.used.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// returns `false`, charging nothing, if `size` exceeds the rest of the budget
+    pub(crate) fn try_charge(&self, size: usize) -> bool {
+        use std::sync::atomic::Ordering;
+        self.0.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| used.checked_add(size).filter(|&used| used <= self.0.limit))
+            .is_ok()
+    }
+
+    pub(crate) fn release(&self, size: usize) {
+        self.0.used.fetch_sub(size, std::sync::atomic::Ordering::AcqRel);
+    }
+}
+const _: () = {
+    impl PartialEq for ReassemblyBudget {
+        fn eq(&self, other: &Self) -> bool {
+            std::sync::Arc::ptr_eq(&self.0, &other.0)
+        }
+    }
+
+    impl std::fmt::Debug for ReassemblyBudget {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("ReassemblyBudget")
+                .field("limit", &self.limit())
+                .field("used", &self.used())
+                .finish()
+        }
+    }
+};
+
+/// Limit of concurrent connections shared over the server, for admission control.
+/// 
+/// A [`ConnectionPermit`] taken by `try_acquire` is passed to the connection by
+/// [`WebSocketContext::permit`], and released exactly when its session ends,
+/// i.e. `WebSocket::manage` returns ( or the `WebSocket` is dropped unmanaged ).
+/// 
+/// *example.rs*
+/// ```
+/// # use mews::{WebSocketContext, Connection, ConnectionLimiter};
+/// # type Response = u16;
+/// async fn handle_websocket(
+///     ctx: WebSocketContext<'_>/* from upgrade request */,
+///     tcp: tokio::net::TcpStream,
+///     limiter: &ConnectionLimiter,
+/// ) -> Response {
+///     let Some(permit) = limiter.try_acquire() else {
+///         return 503/* Service Unavailable */
+///     };
+///     let (sign, ws) = ctx.permit(permit).on_upgrade(|conn: Connection| async move {
+///         /* ... */
+///     });
+///     tokio::spawn(ws.manage(tcp));
+/// 
+///     /* `Switching Protocol` response with `sign`... */
+/// #   101
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ConnectionLimiter(std::sync::Arc<Limiter>);
+struct Limiter {
+    max:    usize,
+    active: std::sync::atomic::AtomicUsize,
+}
+impl ConnectionLimiter {
+    pub fn new(max: usize) -> Self {
+        Self(std::sync::Arc::new(Limiter { max, active: std::sync::atomic::AtomicUsize::new(0) }))
+    }
+
+    pub fn max(&self) -> usize {
+        self.0.max
+    }
+
+    /// number of connections currently holding a permit
+    pub fn active(&self) -> usize {
+        self.0.active.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// take a permit for a new connection, or `None` when `max` connections are active
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        use std::sync::atomic::Ordering;
+        self.0.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| (active < self.0.max).then_some(active + 1))
+            .ok()
+            .map(|_| ConnectionPermit(self.0.clone()))
+    }
+}
+
+/// A slot of [`ConnectionLimiter`], released on drop.
+#[must_use = "the slot is released as soon as `ConnectionPermit` is dropped"]
+pub struct ConnectionPermit(std::sync::Arc<Limiter>);
+const _: () = {
+    impl Drop for ConnectionPermit {
+        fn drop(&mut self) {
+            self.0.active.fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
+        }
+    }
+
+    impl std::fmt::Debug for ConnectionLimiter {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("ConnectionLimiter")
+                .field("max", &self.max())
+                .field("active", &self.active())
+                .finish()
+        }
+    }
+
+    impl std::fmt::Debug for ConnectionPermit {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("ConnectionPermit").finish_non_exhaustive()
+        }
+    }
+};
+
+/// Rewriter of outgoing close frames, see `Config::on_close_send`.
+/// 
+/// *example.rs*
+/// ```
+/// # use mews::{Config, CloseHook};
+/// let config = Config {
+///     on_close_send: Some(CloseHook::new(|mut frame| {
+///         frame.reason = None/* don't leak internal details */;
+///         frame
+///     })),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct CloseHook(std::sync::Arc<dyn Fn(CloseFrame) -> CloseFrame + Send + Sync>);
+impl CloseHook {
+    pub fn new(f: impl Fn(CloseFrame) -> CloseFrame + Send + Sync + 'static) -> Self {
+        Self(std::sync::Arc::new(f))
+    }
+
+    pub(crate) fn apply(&self, frame: CloseFrame) -> CloseFrame {
+        (self.0)(frame)
+    }
+}
+const _: () = {
+    impl PartialEq for CloseHook {
+        fn eq(&self, other: &Self) -> bool {
+            std::sync::Arc::ptr_eq(&self.0, &other.0)
+        }
+    }
+
+    impl std::fmt::Debug for CloseHook {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("CloseHook").finish_non_exhaustive()
+        }
+    }
+};
+
+/// Rate of receiving a frame, see `Config::min_read_rate`.
+/// 
+/// The average rate since the first byte of the frame is checked once
+/// `window` has passed, so that a slow start isn't mistaken for an attack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReadRate {
+    pub bytes_per_second: u64,
+    pub window:           std::time::Duration,
+}
+
+/// Policy on a frame with a reserved opcode, see `Config::on_unknown_opcode`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownOpcode {
+    /// close the connection with `CloseCode::Protocol` (1002), as RFC 6455 requires.
+    Close,
+    /// deliver each such frame to the handler as `Message::Reserved`,
+    /// without reassembling fragments.
+    /// 
+    /// **note** : This is non-conformant to RFC 6455 and only meant for
+    /// protocol experimentation.
+    Deliver,
+}
+
+/// Policy on the closing when the handler finishes, see `Config::handler_exit_close`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandlerExitClose {
+    /// await the peer's close frame, discarding any other message, up to `timeout`
+    /// before the connection is dropped, completing the closing handshake.
+    /// 
+    /// **note** : When the handler has split the connection, the peer's close
+    /// frame is received only while its `ReadHalf` is alive, e.g. in another task.
+    Graceful { timeout: std::time::Duration },
+    /// drop the connection just after sending the close frame, without waiting
+    /// for the peer's one, releasing it sooner in high-churn servers.
+    Immediate,
+}
+
 pub type Handler<C> = Box<dyn
-    FnOnce(Connection<C>) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'static>>
+    FnOnce(Connection<C>) -> std::pin::Pin<Box<dyn std::future::Future<Output = Option<CloseFrame>> + Send + 'static>>
     + Send + Sync
 >;
 
+/// Output of a handler, deciding how the connection is closed when
+/// the handler finishes :
+/// 
+/// * `()` : `Config::default_close` (or `CloseCode::Normal`)
+/// * `CloseFrame` : the frame
+/// * `Result<(), E>` / `Result<CloseFrame, E>` : the same as above on `Ok`,
+///   and `CloseCode::Error` (1011) on `Err`
+/// 
+/// This does nothing if the handler has already closed the connection.
+pub trait HandlerOutput: Send + 'static {
+    /// the close frame to send, `None` for the default one
+    fn into_close_frame(self) -> Option<CloseFrame>;
+}
+const _: () = {
+    impl HandlerOutput for () {
+        fn into_close_frame(self) -> Option<CloseFrame> {
+            None
+        }
+    }
+    impl HandlerOutput for CloseFrame {
+        fn into_close_frame(self) -> Option<CloseFrame> {
+            Some(self)
+        }
+    }
+    impl<T: HandlerOutput, E: std::fmt::Display + Send + 'static> HandlerOutput for Result<T, E> {
+        fn into_close_frame(self) -> Option<CloseFrame> {
+            match self {
+                Ok(output) => output.into_close_frame(),
+                Err(_e) => {
+                    #[cfg(debug_assertions)] eprintln!("WebSocket handler failed: {_e}");
+                    Some(CloseFrame { code: CloseCode::Error, reason: None })
+                }
+            }
+        }
+    }
+};
+
 /// *example.rs*
 /// ```
 /// # use tokio::time::{sleep, Duration};
@@ -47,7 +443,7 @@ pub type Handler<C> = Box<dyn
 ///     );
 /// 
 ///     let (sign, ws) = ctx.on_upgrade(
-///         |mut conn: Connection| async move {
+///         |conn: Connection| async move {
 ///             while let Ok(Some(Message::Text(text))) = conn.recv().await {
 ///                 conn.send(text).await
 ///                     .expect("failed to send message");
@@ -62,13 +458,30 @@ pub type Handler<C> = Box<dyn
 /// }
 /// ```
 pub struct WebSocketContext<'ctx> {
-    sec_websocket_key: &'ctx str,
-    config:            Config,
+    sec_websocket_key:      &'ctx str,
+    sec_websocket_protocol: Option<&'ctx str>,
+    guid:                   &'ctx str,
+    negotiate_protocol:     Option<NegotiateProtocol<'ctx>>,
+    greeting:               Option<Message>,
+    permit:                 Option<ConnectionPermit>,
+    config:                 Config,
 }
+
+/// picks the subprotocol from the offered ones, see [`WebSocketContext::negotiate_protocol`]
+type NegotiateProtocol<'ctx> = Box<dyn FnOnce(&[&str]) -> Option<String> + 'ctx>;
+
 impl<'ctx> WebSocketContext<'ctx> {
     /// create `WebSocketContext` with `Sec-WebSocket-Key` request header value.
     pub fn new(sec_websocket_key: &'ctx str) -> Self {
-        Self { sec_websocket_key, config: Config::default() }
+        Self {
+            sec_websocket_key,
+            sec_websocket_protocol: None,
+            guid:                   GUID,
+            negotiate_protocol:     None,
+            greeting:               None,
+            permit:                 None,
+            config:                 Config::default(),
+        }
     }
 
     pub fn with(mut self, config: Config) -> Self {
@@ -76,29 +489,153 @@ impl<'ctx> WebSocketContext<'ctx> {
         self
     }
 
+    /// send `message` first on the connection, before the handler starts,
+    /// e.g. for protocols where the server greets the client right after
+    /// the handshake.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{WebSocketContext, Connection};
+    /// let (sign, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+    ///     .greeting("hello")
+    ///     .on_upgrade(|conn: Connection| async move {
+    ///         /* the client has already been sent "hello" */
+    ///     });
+    /// ```
+    pub fn greeting(mut self, message: impl Into<Message>) -> Self {
+        self.greeting = Some(message.into());
+        self
+    }
+
+    /// hold `permit` of a [`ConnectionLimiter`] for the connection, releasing it
+    /// when the session ends.
+    pub fn permit(mut self, permit: ConnectionPermit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    /// set `Sec-WebSocket-Protocol` request header value, the subprotocols
+    /// offered by the client.
+    pub fn sec_websocket_protocol(mut self, sec_websocket_protocol: &'ctx str) -> Self {
+        self.sec_websocket_protocol = Some(sec_websocket_protocol);
+        self
+    }
+
+    /// **NON-STANDARD** : sign `Sec-WebSocket-Key` with `guid` instead of
+    /// the RFC 6455 one ( `258EAFA5-E914-47DA-95CA-C5AB0DC85B11` ).
+    /// 
+    /// This is only for testing, e.g. simulating a misbehaving server in
+    /// negative tests. **Never use it in production**, as any conforming
+    /// client rejects the handshake.
+    pub fn nonstandard_guid(mut self, guid: &'ctx str) -> Self {
+        self.guid = guid;
+        self
+    }
+
+    /// choose the subprotocol from the client's offered list by `negotiate`.
+    /// 
+    /// The list is in the order offered, with each entry trimmed, and empty
+    /// entries and duplicates removed.
+    /// 
+    /// The chosen one is available by `WebSocket::protocol` to be echoed as
+    /// `Sec-WebSocket-Protocol` response header, and by `Connection::protocol`
+    /// in the handler.
+    /// 
+    /// **note** : The returned value is ignored if it's not one of the offered.
+    /// So when the client offered none, no subprotocol is chosen even if
+    /// `negotiate` returns a default, and `Sec-WebSocket-Protocol` must be
+    /// omitted from the response, as RFC 6455 requires.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::WebSocketContext;
+    /// let ctx = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+    ///     .sec_websocket_protocol("chat.v1, chat.v2")
+    ///     .negotiate_protocol(|offered| {
+    ///         ["chat.v2", "chat.v1"].into_iter()
+    ///             .find(|p| offered.contains(p))
+    ///             .map(String::from)
+    ///     });
+    /// ```
+    pub fn negotiate_protocol(mut self, negotiate: impl FnOnce(&[&str]) -> Option<String> + 'ctx) -> Self {
+        self.negotiate_protocol = Some(Box::new(negotiate));
+        self
+    }
+
     /// create `Sec-WebSocket-Accept` value and a `WebSocket` with the handler.
     /// 
     /// ## handler
     /// 
-    /// any 'static `FnOnce(Connection<C>) -> {impl Future<Output = ()> + Send} + Send + Sync`
+    /// any 'static `FnOnce(Connection<C>) -> {impl Future<Output = impl HandlerOutput> + Send} + Send + Sync`
+    /// 
+    /// see [`HandlerOutput`] for how the output closes the connection.
     pub fn on_upgrade<C: UnderlyingConnection, H, F>(self, handler: H) -> (String, WebSocket<C>)
     where
         H: FnOnce(Connection<C>) -> F + Send + Sync + 'static,
-        F: std::future::Future<Output = ()> + Send + 'static
+        F: std::future::Future<Output: HandlerOutput> + Send + 'static
     {
-        (
-            sign(self.sec_websocket_key),
-            WebSocket {
-                config:  self.config,
-                handler: Box::new(|c| Box::pin(handler(c)))
-            }
-        )
+        let ws = self.on_upgrade_lazy(handler);
+        (ws.sec_websocket_accept().to_owned(), ws)
+    }
+
+    /// Same as [`on_upgrade`](WebSocketContext::on_upgrade), but defers signing
+    /// `Sec-WebSocket-Key` until [`WebSocket::sec_websocket_accept`] is called,
+    /// skipping it for connections rejected before the response.
+    pub fn on_upgrade_lazy<C: UnderlyingConnection, H, F>(self, handler: H) -> WebSocket<C>
+    where
+        H: FnOnce(Connection<C>) -> F + Send + Sync + 'static,
+        F: std::future::Future<Output: HandlerOutput> + Send + 'static
+    {
+        let protocol = self.negotiate_protocol.and_then(|negotiate| {
+            let offered = self.sec_websocket_protocol.map(offered_protocols).unwrap_or_default();
+            negotiate(&offered).filter(|chosen| offered.contains(&&**chosen))
+        });
+
+        WebSocket {
+            config:  self.config,
+            protocol,
+            greeting: self.greeting,
+            permit:   self.permit,
+            sec_websocket_key:    self.sec_websocket_key.to_owned(),
+            guid:                 self.guid.to_owned(),
+            sec_websocket_accept: std::sync::OnceLock::new(),
+            handler: Box::new(|c| Box::pin(async {handler(c).await.into_close_frame()}))
+        }
+    }
+
+    /// Same as [`on_upgrade`](WebSocketContext::on_upgrade), but the handler
+    /// also takes the [`ConnectionInfo`] up front.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{WebSocketContext, Connection, ConnectionInfo, CloseCode};
+    /// # fn f(ctx: WebSocketContext<'_>) {
+    /// let (sign, ws) = ctx.on_upgrade_with_info(
+    ///     |info: ConnectionInfo, conn: Connection| async move {
+    ///         if info.protocol.as_deref() != Some("chat.v1") {
+    ///             return conn.close_code(CloseCode::Policy).await
+    ///         }
+    ///         println!("{:?} connected", info.peer_addr);
+    ///         /* ... */
+    /// #       Ok(())
+    ///     }
+    /// );
+    /// # }
+    /// ```
+    pub fn on_upgrade_with_info<C: UnderlyingConnection, H, F>(self, handler: H) -> (String, WebSocket<C>)
+    where
+        H: FnOnce(ConnectionInfo, Connection<C>) -> F + Send + Sync + 'static,
+        F: std::future::Future<Output: HandlerOutput> + Send + 'static
+    {
+        self.on_upgrade(|conn: Connection<C>| handler(conn.info(), conn))
     }
 }
 const _: () = {
     impl PartialEq for WebSocketContext<'_> {
         fn eq(&self, other: &Self) -> bool {
             self.sec_websocket_key == other.sec_websocket_key &&
+            self.sec_websocket_protocol == other.sec_websocket_protocol &&
+            self.guid == other.guid &&
             self.config == other.config
         }
     }
@@ -106,7 +643,9 @@ const _: () = {
     impl std::fmt::Debug for WebSocketContext<'_> {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             f.debug_struct("WebSocketContext")
-                .field("Sec-WebSocket-Context", &self.sec_websocket_key)
+                .field("Sec-WebSocket-Key", &self.sec_websocket_key)
+                .field("Sec-WebSocket-Protocol", &self.sec_websocket_protocol)
+                .field("guid", &self.guid)
                 .field("config", &self.config)
                 .finish()
         }
@@ -130,7 +669,7 @@ const _: () = {
 ///     tcp: TcpStream
 /// ) -> Response {
 ///     let (sign, ws) = ctx.on_upgrade(
-///         |mut conn: Connection| async move {
+///         |conn: Connection| async move {
 ///             while let Ok(Some(Message::Text(text))) = conn.recv().await {
 ///                 conn.send(text).await
 ///                     .expect("failed to send message");
@@ -146,50 +685,118 @@ const _: () = {
 /// ```
 #[must_use = "`WebSocket` does nothing unless `.manage()` or `.manage_with_timeout()` is called"]
 pub struct WebSocket<C: UnderlyingConnection = runtime::TcpStream> {
-    config:  Config,
-    handler: Handler<C>,
+    config:   Config,
+    protocol: Option<String>,
+    sec_websocket_key:    String,
+    guid:                 String,
+    sec_websocket_accept: std::sync::OnceLock<String>,
+    greeting: Option<Message>,
+    permit:   Option<ConnectionPermit>,
+    handler:  Handler<C>,
 }
 impl<C: UnderlyingConnection> WebSocket<C> {
+    /// the negotiated subprotocol to be echoed as `Sec-WebSocket-Protocol`
+    /// response header, if any. see [`WebSocketContext::negotiate_protocol`].
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// the `Sec-WebSocket-Accept` value signed from the client's
+    /// `Sec-WebSocket-Key`, the same as returned by `on_upgrade`.
+    /// 
+    /// This signs it on the first call for `WebSocketContext::on_upgrade_lazy`.
+    /// 
+    /// *example.rs*
+    /// ```
+    /// # use mews::{WebSocketContext, Connection};
+    /// let (sign, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+    ///     .on_upgrade(|_: Connection| async {});
+    /// 
+    /// assert_eq!(ws.sec_websocket_accept(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    /// assert_eq!(ws.sec_websocket_accept(), sign);
+    /// 
+    /// let ws = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+    ///     .on_upgrade_lazy(|_: Connection| async {});
+    /// assert_eq!(ws.sec_websocket_accept(), sign);
+    /// ```
+    pub fn sec_websocket_accept(&self) -> &str {
+        self.sec_websocket_accept.get_or_init(|| sign(&self.sec_websocket_key, &self.guid))
+    }
+
     /// manage a WebSocket session on the connection.
+    /// 
+    /// When the handler finishes without closing the connection, the close
+    /// frame decided by its [`HandlerOutput`] is sent to the peer.
     pub async fn manage(self, conn: C) {
-        let (conn, closer) = Connection::new(conn, self.config);
-        (self.handler)(conn).await;
-        closer.send_close_if_not_closed().await;
+        let _permit = self.permit;
+        let (mut conn, closer) = Connection::new(conn, self.config);
+        conn.protocol = self.protocol;
+        if let Some(greeting) = self.greeting {
+            if let Err(frame) = greet(&conn, greeting).await {
+                drop(conn);
+                return closer.send_close_if_not_closed_with(frame).await
+            }
+        }
+        match (self.handler)(conn).await {
+            Some(frame) => closer.send_close_if_not_closed_with(frame).await,
+            None        => closer.send_close_if_not_closed().await,
+        }
     }
 
     /// manage a WebSocket session on the connection with timeout.
     /// 
     /// returns `true` if session has been aborted by the timeout.
     pub async fn manage_with_timeout(self, timeout: std::time::Duration, conn: C) -> bool {
-        let (conn, closer) = Connection::new(conn, self.config);
-
-        let is_timeouted = with_timeout(timeout,
-            (self.handler)(conn)
-        ).await.is_none();
-
-        if is_timeouted {
-            closer.send_close_if_not_closed_with(CloseFrame {
-                code:   CloseCode::Library(4000),
-                reason: Some("timeout".into())
-            }).await;
-            true
-        } else {
-            closer.send_close_if_not_closed_with(CloseFrame {
-                code:   CloseCode::Normal,
-                reason: None
-            }).await;
-            false
+        let _permit = self.permit;
+        let (mut conn, closer) = Connection::new(conn, self.config);
+        conn.protocol = self.protocol;
+        if let Some(greeting) = self.greeting {
+            if let Err(frame) = greet(&conn, greeting).await {
+                drop(conn);
+                closer.send_close_if_not_closed_with(frame).await;
+                return false
+            }
+        }
+
+        match with_timeout(timeout, (self.handler)(conn)).await {
+            None => {
+                closer.send_close_if_not_closed_with(CloseFrame {
+                    code:   CloseCode::Library(4000),
+                    reason: Some("timeout".into())
+                }).await;
+                true
+            }
+            Some(Some(frame)) => {
+                closer.send_close_if_not_closed_with(frame).await;
+                false
+            }
+            Some(None) => {
+                closer.send_close_if_not_closed().await;
+                false
+            }
         }
     }
 }
+/// send the greeting, or the close frame ( 1011 ) to end the session
+/// with when it fails, without running the handler.
+async fn greet<C: UnderlyingConnection>(conn: &Connection<C>, greeting: Message) -> Result<(), CloseFrame> {
+    conn.send(greeting).await.map_err(|_e| {
+        #[cfg(debug_assertions)] eprintln!("failed to send the greeting: {_e}");
+        CloseFrame { code: CloseCode::Error, reason: None }
+    })
+}
+
 const _: () = {
     impl<C: UnderlyingConnection> PartialEq for WebSocket<C>
     where
-        dyn FnOnce(Connection<C>) -> std::pin::Pin<Box<(dyn std::future::Future<Output = ()> + Send + 'static)>> + Send + Sync
+        dyn FnOnce(Connection<C>) -> std::pin::Pin<Box<(dyn std::future::Future<Output = Option<CloseFrame>> + Send + 'static)>> + Send + Sync
         : PartialEq
     {
         fn eq(&self, other: &Self) -> bool {
             self.config == other.config &&
+            self.protocol == other.protocol &&
+            self.sec_websocket_key == other.sec_websocket_key &&
+            self.guid == other.guid &&
             &self.handler == &other.handler
         }
     }
@@ -198,19 +805,263 @@ const _: () = {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             f.debug_struct("WebSocket")
                 .field("config", &self.config)
+                .field("protocol", &self.protocol)
+                .field("Sec-WebSocket-Key", &self.sec_websocket_key)
+                .field("greeting", &self.greeting)
                 .finish_non_exhaustive()
         }
     }
 };
 
+/// subprotocols in `Sec-WebSocket-Protocol` value, in the order offered :
+/// trimmed, without empty entries nor duplicates ( tokens are case-sensitive ).
+fn offered_protocols(sec_websocket_protocol: &str) -> Vec<&str> {
+    let mut offered = Vec::new();
+    for p in sec_websocket_protocol.split(',').map(str::trim) {
+        if !p.is_empty() && !offered.contains(&p) {
+            offered.push(p);
+        }
+    }
+    offered
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_greeting() {
+    use tokio::io::AsyncReadExt;
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (mut client, server) = crate::__test__::tcp_pair().await;
+
+        let (_, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+            .greeting("hello")
+            .on_upgrade(|conn: Connection| async move {
+                conn.send("from handler").await.unwrap();
+            });
+        ws.manage(server).await;
+
+        let mut bytes = [0; 7 + 14 + 4];
+        client.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x81\x05hello\x81\x0cfrom handler\x88\x02\x03\xe8");
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_greeting_failed() {
+    use tokio::io::AsyncReadExt;
+
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (mut client, server) = crate::__test__::tcp_pair().await;
+
+        let (_, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+            .greeting(Message::Reserved { opcode: 0x1/* not reserved */, payload: vec![] })
+            .on_upgrade(|conn: Connection| async move {
+                conn.send("from handler").await.unwrap();
+            });
+        ws.manage(server).await;
+
+        let mut bytes = Vec::new();
+        client.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x88\x02\x03\xf3", "closed with 1011 without running the handler");
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_handler_exit_close() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::time::{Duration, Instant};
+
+    fn websocket(handler_exit_close: HandlerExitClose) -> WebSocket {
+        let (_, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+            .with(Config { handler_exit_close, ..Default::default() })
+            .on_upgrade(|_: Connection| async {/* no-op */});
+        ws
+    }
+
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        /* `Immediate` : done without the peer's close frame */
+        let (mut client, server) = crate::__test__::tcp_pair().await;
+        let started = Instant::now();
+        websocket(HandlerExitClose::Immediate).manage(server).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let mut bytes = Vec::new();
+        client.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, *b"\x88\x02\x03\xe8");
+
+        /* `Graceful` : done by the peer's close frame */
+        let (mut client, server) = crate::__test__::tcp_pair().await;
+        let managed = tokio::spawn(websocket(HandlerExitClose::Graceful { timeout: Duration::from_secs(10) }).manage(server));
+        let mut close = [0; 4];
+        client.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, *b"\x88\x02\x03\xe8");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!managed.is_finished(), "awaiting the peer's close frame");
+        client.write_all(b"\x81\x81\x00\x00\x00\x00a\x88\x82\x00\x00\x00\x00\x03\xe8").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), managed).await.unwrap().unwrap();
+        assert_eq!(client.read(&mut close).await.unwrap(), 0, "dropped after the closing handshake");
+
+        /* `Graceful` : done by the timeout without the peer's close frame */
+        let (_client, server) = crate::__test__::tcp_pair().await;
+        let started = Instant::now();
+        websocket(HandlerExitClose::Graceful { timeout: Duration::from_millis(100) }).manage(server).await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    })
+}
+
+#[cfg(all(test, feature="rt_tokio", feature="DEBUG"))]
+#[test] fn test_connection_limiter() {
+    tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap().block_on(async {
+        let (_client, server) = crate::__test__::tcp_pair().await;
+
+        let limiter = ConnectionLimiter::new(1);
+        let permit = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none(), "the next handshake is rejected at the cap");
+
+        let (_, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+            .permit(permit)
+            .on_upgrade(|_: Connection| async move {});
+        assert_eq!(limiter.active(), 1);
+        ws.manage(server).await;
+        assert_eq!(limiter.active(), 0);
+
+        let (_, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+            .permit(limiter.try_acquire().unwrap())
+            .on_upgrade(|_: Connection| async move {});
+        drop::<WebSocket>(ws);
+        assert!(limiter.try_acquire().is_some(), "released by dropping the unmanaged `WebSocket`");
+    })
+}
+
+#[cfg(test)]
+#[test] fn test_offered_protocols() {
+    assert_eq!(offered_protocols(" chat.v2 ,, chat.v1,chat.v2 ,\tCHAT.v1 , "), ["chat.v2", "chat.v1", "CHAT.v1"]);
+
+    let (_, ws) = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==")
+        .sec_websocket_protocol("a, b ,c,b")
+        .negotiate_protocol(|offered| {
+            assert_eq!(offered, ["a", "b", "c"]);
+            Some("B".into()/* not offered : case-sensitive */)
+        })
+        .on_upgrade(|_: Connection| async {});
+    assert_eq!(ws.protocol(), None);
+
+    for offer in [None, Some(""), Some(" , ")] {
+        let mut ctx = WebSocketContext::new("dGhlIHNhbXBsZSBub25jZQ==");
+        if let Some(offer) = offer {
+            ctx = ctx.sec_websocket_protocol(offer);
+        }
+        let (_, ws) = ctx
+            .negotiate_protocol(|offered| {
+                assert!(offered.is_empty());
+                Some("default".into())
+            })
+            .on_upgrade(|_: Connection| async {});
+        assert_eq!(ws.protocol(), None);
+    }
+}
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Verify `Sec-WebSocket-Accept` returned by a server ( `received_accept` ) against
+/// `Sec-WebSocket-Key` sent by the client ( `sent_key` ), e.g. to abort the
+/// handshake with a non-conforming server in client mode.
+/// 
+/// ```
+/// assert!(mews::verify_accept("dGhlIHNhbXBsZSBub25jZQ==", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+/// ```
+pub fn verify_accept(sent_key: &str, received_accept: &str) -> bool {
+    sign(sent_key, GUID) == received_accept.trim()
+}
+
+/// Build the HTTP request upgrading to WebSocket, e.g. to write it to
+/// a stream by yourself in client mode.
+/// 
+/// `Sec-WebSocket-Protocol` / `Sec-WebSocket-Extensions` is included only
+/// when `protocols` / `extensions` isn't empty. `key` is sent as it is, so
+/// it must be a base64-encoded random 16-byte nonce.
+/// 
+/// Fails with `InvalidInput` when `host`, `path` or `key` contains anything
+/// but visible ASCII, or a protocol / extension contains anything but visible
+/// ASCII and spaces, so that no `\r` / `\n` can inject a header or a request.
+/// 
+/// ```
+/// let request = mews::build_client_request("example.com", "/chat", "dGhlIHNhbXBsZSBub25jZQ==", &["chat"], &[]).unwrap();
+/// assert!(request.starts_with(b"GET /chat HTTP/1.1\r\nHost: example.com\r\n"));
+/// 
+/// assert!(mews::build_client_request("example.com", "/chat\r\nX-Injected: 1", "dGhlIHNhbXBsZSBub25jZQ==", &[], &[]).is_err());
+/// ```
+pub fn build_client_request(host: &str, path: &str, key: &str, protocols: &[&str], extensions: &[&str]) -> Result<Vec<u8>, std::io::Error> {
+    fn check(name: &str, value: &str, allow_space: bool) -> Result<(), std::io::Error> {
+        if value.bytes().all(|b| b.is_ascii_graphic() || (allow_space && b == b' ')) {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+                "`{name}` of WebSocket request contains a character other than visible ASCII: {value:?}"
+            )))
+        }
+    }
+    check("host", host, false)?;
+    check("path", path, false)?;
+    check("key", key, false)?;
+    for protocol in protocols {check("protocol", protocol, true)?}
+    for extension in extensions {check("extension", extension, true)?}
+
+    let mut request = format!("\
+        GET {path} HTTP/1.1\r\n\
+        Host: {host}\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: {key}\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+    ");
+    if !protocols.is_empty() {
+        request.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocols.join(", ")));
+    }
+    if !extensions.is_empty() {
+        request.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", extensions.join(", ")));
+    }
+    request.push_str("\r\n");
+    Ok(request.into_bytes())
+}
+
+#[cfg(test)]
+#[test] fn test_build_client_request() {
+    /* example of https://datatracker.ietf.org/doc/html/rfc6455#section-1.2 */
+    assert_eq!(
+        String::from_utf8(build_client_request("server.example.com", "/chat", "dGhlIHNhbXBsZSBub25jZQ==", &["chat", "superchat"], &[]).unwrap()).unwrap(),
+        "\
+        GET /chat HTTP/1.1\r\n\
+        Host: server.example.com\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Protocol: chat, superchat\r\n\
+        \r\n\
+        "
+    );
+    assert!(String::from_utf8(build_client_request("localhost:3000", "/", "dGhlIHNhbXBsZSBub25jZQ==", &[], &["permessage-deflate; client_max_window_bits"]).unwrap()).unwrap()
+        .ends_with("Sec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n"));
+
+    for (host, path, protocol) in [
+        ("example.com\r\nX-Injected: 1", "/", "chat"),
+        ("example.com", "/chat HTTP/1.1", "chat"),
+        ("example.com", "/chat\n", "chat"),
+        ("example.com", "/ch\u{e4}t", "chat"),
+        ("example.com", "/chat", "chat\r\n\r\nGET / HTTP/1.1"),
+    ] {
+        let error = build_client_request(host, path, "dGhlIHNhbXBsZSBub25jZQ==", &[protocol], &[]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
 #[inline]
-fn sign(sec_websocket_key: &str) -> String {
+fn sign(sec_websocket_key: &str, guid: &str) -> String {
     use ::sha1::{Sha1, Digest};
     use ::base64::engine::{Engine, general_purpose::STANDARD};
 
     let mut sha1 = <Sha1 as Digest>::new();
     sha1.update(sec_websocket_key.as_bytes());
-    sha1.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    sha1.update(guid.as_bytes());
 
     Engine::encode(&STANDARD, sha1.finalize())
 }
@@ -218,11 +1069,15 @@ fn sign(sec_websocket_key: &str) -> String {
 #[cfg(test)]
 #[test] fn test_sign() {
     /* example of https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers#server_handshake_response */
-    assert_eq!(sign("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    assert_eq!(sign("dGhlIHNhbXBsZSBub25jZQ==", GUID), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    assert!(verify_accept("dGhlIHNhbXBsZSBub25jZQ==", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert!(!verify_accept("dGhlIHNhbXBsZSBub25jZQ==", "dGhlIHNhbXBsZSBub25jZQ=="));
+    assert!(!verify_accept("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", "dGhlIHNhbXBsZSBub25jZQ=="));
 }
 
 #[inline]
-fn with_timeout<T>(
+pub(crate) fn with_timeout<T>(
     duration: std::time::Duration,
     task: impl std::future::Future<Output = T>
 ) -> impl std::future::Future<Output = Option<T>> {